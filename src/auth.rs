@@ -0,0 +1,115 @@
+use axum::{
+    body::BoxBody,
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+        Request, Response, StatusCode,
+    },
+    response::IntoResponse,
+};
+use std::error::Error;
+use tower_http::auth::{AuthorizeRequest, RequireAuthorizationLayer};
+
+use crate::error::ErrorBody;
+use crate::logging;
+use crate::web_config::{read_secret, GroupAuth};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Accepted credentials for one route group, read from their files at startup
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    basic: Option<String>,
+    bearer: Option<String>,
+}
+
+impl Credentials {
+    pub fn load(config: &GroupAuth) -> BoxResult<Self> {
+        let basic = match &config.basic_auth {
+            Some(basic) => {
+                let password = read_secret(&basic.password_file)?;
+                let encoded = base64::encode(format!("{}:{}", basic.username, password));
+                logging::redact(&password)?;
+                logging::redact(&encoded)?;
+                Some(format!("Basic {encoded}"))
+            }
+            None => None,
+        };
+
+        let bearer = match &config.bearer_token_file {
+            Some(path) => {
+                let token = read_secret(path)?;
+                logging::redact(&token)?;
+                Some(format!("Bearer {token}"))
+            }
+            None => None,
+        };
+
+        if basic.is_none() && bearer.is_none() {
+            return Err("Auth group needs basic_auth or bearer_token_file".into());
+        }
+
+        Ok(Credentials { basic, bearer })
+    }
+
+    // Build a layer for the group, or None when the group is left open
+    pub fn layer(config: &Option<GroupAuth>) -> BoxResult<Option<RequireAuthorizationLayer<Self>>> {
+        match config {
+            Some(config) => Ok(Some(RequireAuthorizationLayer::custom(Credentials::load(
+                config,
+            )?))),
+            None => Ok(None),
+        }
+    }
+}
+
+// Compare without returning early, so timing does not leak how much of a secret matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl AuthorizeRequest for Credentials {
+    type Output = ();
+    type ResponseBody = BoxBody;
+
+    fn authorize<B>(&mut self, request: &Request<B>) -> Option<Self::Output> {
+        let header = request.headers().get(AUTHORIZATION)?.as_bytes();
+
+        let accepted = [&self.basic, &self.bearer]
+            .into_iter()
+            .flatten()
+            .any(|expected| constant_time_eq(header, expected.as_bytes()));
+
+        match accepted {
+            true => Some(()),
+            false => None,
+        }
+    }
+
+    fn unauthorized_response<B>(&mut self, request: &Request<B>) -> Response<Self::ResponseBody> {
+        tracing::info!(path = request.uri().path(), "unauthorized");
+
+        let challenge = match self.basic {
+            Some(_) => "Basic realm=\"mongo-atlas-billing-exporter\"",
+            None => "Bearer",
+        };
+
+        let error = ErrorBody {
+            kind: "unauthorized",
+            error: "Status: Unauthorized".to_string(),
+            path: Some(request.uri().path().to_string()),
+            ..ErrorBody::default()
+        };
+        (
+            StatusCode::UNAUTHORIZED,
+            [
+                (WWW_AUTHENTICATE, challenge),
+                (CONTENT_TYPE, "application/json"),
+            ],
+            error.to_string(),
+        )
+            .into_response()
+    }
+}
@@ -0,0 +1,195 @@
+use serde::Serialize;
+
+use crate::error::{AtlasError, Error as RestError};
+use crate::State;
+
+// Outcome of a single self-check step
+#[derive(Serialize, Debug, Clone)]
+pub struct Step {
+    pub name: &'static str,
+    pub ok: bool,
+    pub skipped: bool,
+    pub status: Option<u16>,
+    pub error_code: Option<String>,
+    pub diagnosis: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    pub ok: bool,
+    pub steps: Vec<Step>,
+}
+
+// Step names in the order they are checked
+static STEPS: [&str; 4] = ["reachability", "digest_auth", "org_access", "invoice_read"];
+
+impl Step {
+    fn passed(name: &'static str, status: Option<u16>, diagnosis: &str) -> Self {
+        Step {
+            name,
+            ok: true,
+            skipped: false,
+            status,
+            error_code: None,
+            diagnosis: diagnosis.to_string(),
+        }
+    }
+
+    fn failed(
+        name: &'static str,
+        status: Option<u16>,
+        error: &AtlasError,
+        diagnosis: &str,
+    ) -> Self {
+        let diagnosis = match error.detail.as_ref().or(error.reason.as_ref()) {
+            Some(detail) => format!("{diagnosis} Atlas said: {detail}"),
+            None => diagnosis.to_string(),
+        };
+
+        Step {
+            name,
+            ok: false,
+            skipped: false,
+            status,
+            error_code: error.error_code.clone(),
+            diagnosis,
+        }
+    }
+
+    fn skipped(name: &'static str) -> Self {
+        Step {
+            name,
+            ok: false,
+            skipped: true,
+            status: None,
+            error_code: None,
+            diagnosis: "Skipped, a previous step failed".to_string(),
+        }
+    }
+}
+
+// Explain common authenticated failures, shared by the later steps
+fn diagnose(status: u16, error: &AtlasError, forbidden: &str) -> String {
+    match error.error_code.as_deref() {
+        Some("IP_ADDRESS_NOT_ON_ACCESS_LIST") | Some("ORG_REQUIRES_ACCESS_LIST") => {
+            "The exporter's IP address is not on the API key access list.".to_string()
+        }
+        Some("INVALID_ORG_ID") | Some("ORG_NOT_FOUND") => {
+            "The org id is invalid or does not exist.".to_string()
+        }
+        _ => match status {
+            401 => "The public or private key is wrong.".to_string(),
+            403 => forbidden.to_string(),
+            404 => "The org id does not exist or the key cannot see it.".to_string(),
+            429 => "Atlas is rate limiting the key, try again later.".to_string(),
+            _ => format!("Atlas answered with unexpected status {status}."),
+        },
+    }
+}
+
+async fn check_authenticated(
+    state: &State,
+    name: &'static str,
+    path: &str,
+    passed: &str,
+    forbidden: &str,
+) -> Step {
+    let response = match state.get_raw(path).await {
+        Ok(response) => response,
+        Err(RestError::MissingHeader) | Err(RestError::UnexpectedCode(_)) => {
+            return Step::failed(
+                name,
+                None,
+                &AtlasError::default(),
+                "Atlas did not offer digest authentication.",
+            )
+        }
+        Err(e) => {
+            return Step::failed(
+                name,
+                None,
+                &AtlasError::default(),
+                &format!("Request failed: {}", e.message()),
+            )
+        }
+    };
+
+    let status = response.status().as_u16();
+    match status {
+        200 => Step::passed(name, Some(status), passed),
+        _ => {
+            let error = AtlasError::read(response, state.request_timeout).await;
+            let diagnosis = diagnose(status, &error, forbidden);
+            Step::failed(name, Some(status), &error, &diagnosis)
+        }
+    }
+}
+
+async fn run_step(state: &State, name: &'static str) -> Step {
+    match name {
+        "reachability" => match state.get_unauthenticated("").await {
+            Ok(response) => Step::passed(
+                name,
+                Some(response.status().as_u16()),
+                "Atlas can be reached.",
+            ),
+            Err(e) => Step::failed(
+                name,
+                None,
+                &AtlasError::default(),
+                &format!(
+                    "Atlas cannot be reached, check DNS, proxies and egress rules: {}",
+                    e.message()
+                ),
+            ),
+        },
+        "digest_auth" => {
+            check_authenticated(
+                state,
+                name,
+                "orgs",
+                "The keys are valid.",
+                "The keys were rejected.",
+            )
+            .await
+        }
+        "org_access" => {
+            check_authenticated(
+                state,
+                name,
+                &format!("orgs/{}", state.org),
+                "The key has access to the org.",
+                "The key does not belong to the org.",
+            )
+            .await
+        }
+        _ => {
+            check_authenticated(
+                state,
+                name,
+                &format!("orgs/{}/invoices?itemsPerPage=1", state.org),
+                "The key can read invoices.",
+                "The key is missing the Org Billing Viewer role.",
+            )
+            .await
+        }
+    }
+}
+
+// Check each step in order, skipping the rest once one fails
+pub async fn run(state: &State) -> Report {
+    let mut steps = Vec::new();
+    let mut ok = true;
+
+    for name in STEPS {
+        let step = match ok {
+            true => run_step(state, name).await,
+            false => Step::skipped(name),
+        };
+        tracing::info!(step = step.name, ok = step.ok, "check step");
+        ok &= step.ok;
+        steps.push(step);
+    }
+
+    Report { ok, steps }
+}
@@ -1,57 +1,342 @@
 //use serde_json::error::Error as SerdeError;
 use axum::{
     body::{self},
-    http::StatusCode,
+    http::{
+        header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER},
+        Request, StatusCode,
+    },
+    middleware::Next,
     response::{IntoResponse, Response},
 };
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+// Error body returned by Atlas alongside 4xx and 5xx responses
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AtlasError {
+    pub error_code: Option<String>,
+    pub detail: Option<String>,
+    pub reason: Option<String>,
+}
+
+impl AtlasError {
+    // Read the error body of a failed response, ignoring bodies that are not json or do not
+    // arrive within the timeout
+    pub async fn read(response: hyper::Response<hyper::Body>, timeout: Duration) -> AtlasError {
+        match tokio::time::timeout(timeout, hyper::body::to_bytes(response.into_body())).await {
+            Ok(Ok(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+            _ => AtlasError::default(),
+        }
+    }
+}
+
+// Serialized form of an error, used for response bodies and log lines alike
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ErrorBody {
+    pub kind: &'static str,
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    // Path of the exporter request that failed, filled in by error_context
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+impl ErrorBody {
+    // Log the error with each part of the body as its own field
+    pub fn log(&self, msg: &str) {
+        tracing::error!(
+            kind = self.kind,
+            upstream_status = self.upstream_status,
+            error_code = self.error_code.as_deref(),
+            detail = self.detail.as_deref(),
+            reason = self.reason.as_deref(),
+            path = self.path.as_deref(),
+            "{msg}: {}",
+            self.error
+        );
+    }
+}
+
+impl fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
-    Forbidden,
-    Unauthorized,
-    NotFound,
-    UnknownCode,
-    UnexpectedCode,
+    Forbidden(AtlasError),
+    Unauthorized(AtlasError),
+    NotFound(AtlasError),
+    // Atlas answered with a status we have no mapping for
+    UnknownCode(u16, AtlasError),
+    // The first digest request did not get the expected 401
+    UnexpectedCode(u16),
     MissingHeader,
     Hyper(hyper::Error),
     Digest(digest_auth::Error),
     SerdeJson(serde_json::Error),
     InvalidHeaderValue(hyper::header::InvalidHeaderValue),
+    InvalidDate(String, chrono::ParseError),
+    StoreDisabled,
+    StorePoisoned,
+    Sqlite(rusqlite::Error),
+    Join(tokio::task::JoinError),
+    Csv(csv::Error),
+    InvalidColumn(String),
+    InvalidAggregate(String),
+    // An invoice id in the query that is not 24 hex characters
+    InvalidInvoiceId(String),
+    // A from or to date in the query is not YYYY-MM-DD
+    InvalidQueryDate(String, chrono::ParseError),
+    // Atlas answered 429, with the Retry-After seconds when given
+    RateLimited(Option<u64>, AtlasError),
+    // Atlas answered with a 5xx status
+    UpstreamUnavailable(u16, AtlasError),
+    Timeout,
+    // Atlas answered 200 without a field we rely on
+    MissingField(&'static str),
+    // The request to Atlas could not be built, usually because of an odd org id
+    Request(hyper::http::Error),
+    Recorder(metrics_exporter_prometheus::BuildError),
+    // A handler panicked, caught by catch_panic
+    Panic(String),
 }
 
 impl std::error::Error for Error {}
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl Error {
+    // Process exit code for one-shot runs, following sysexits.h
+    pub fn exit_code(&self) -> i32 {
         match *self {
-            Error::Forbidden => f.write_str("{\"error\": \"Status: Forbidden\"}"),
-            Error::UnknownCode => f.write_str("{\"error\": \"Caught bad status code\"}"),
-            Error::Unauthorized => f.write_str("{\"error\": \"Status: Unauthorized\"}"),
-            Error::NotFound => f.write_str("{\"error\": \"Status: Not found\"}"),
-            Error::UnexpectedCode => {
-                f.write_str("{\"error\": \"Unexpected status code received\"}")
+            Error::Unauthorized(_) | Error::Forbidden(_) => 77,
+            Error::NotFound(_) => 66,
+            Error::Hyper(_) | Error::Join(_) | Error::RateLimited(_, _) | Error::Timeout => 75,
+            Error::UpstreamUnavailable(_, _) => 69,
+            Error::UnknownCode(_, _)
+            | Error::UnexpectedCode(_)
+            | Error::MissingHeader
+            | Error::Digest(_)
+            | Error::InvalidHeaderValue(_) => 76,
+            Error::SerdeJson(_) | Error::InvalidDate(_, _) | Error::MissingField(_) => 65,
+            Error::StoreDisabled
+            | Error::InvalidColumn(_)
+            | Error::InvalidAggregate(_)
+            | Error::InvalidInvoiceId(_)
+            | Error::InvalidQueryDate(_, _)
+            | Error::Request(_) => 64,
+            Error::Recorder(_) | Error::Panic(_) => 70,
+            Error::StorePoisoned | Error::Sqlite(_) | Error::Csv(_) => 74,
+        }
+    }
+
+    // Status returned to http clients. Atlas rejecting our credentials or not knowing the org is
+    // a gateway problem, not the scraper's, so these map to 502 instead of 401/403/404. A 404
+    // only ever means the route does not exist.
+    pub fn status(&self) -> StatusCode {
+        match *self {
+            Error::StoreDisabled => StatusCode::NOT_FOUND,
+            Error::InvalidColumn(_)
+            | Error::InvalidAggregate(_)
+            | Error::InvalidInvoiceId(_)
+            | Error::InvalidQueryDate(_, _) => StatusCode::BAD_REQUEST,
+            Error::Hyper(_) | Error::RateLimited(_, _) | Error::UpstreamUnavailable(_, _) => {
+                StatusCode::SERVICE_UNAVAILABLE
             }
-            Error::MissingHeader => {
-                f.write_str("{\"error\": \"Missing expected response header\"}")
+            Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Error::Unauthorized(_)
+            | Error::Forbidden(_)
+            | Error::NotFound(_)
+            | Error::UnknownCode(_, _)
+            | Error::UnexpectedCode(_)
+            | Error::MissingHeader
+            | Error::Digest(_)
+            | Error::InvalidHeaderValue(_)
+            | Error::SerdeJson(_)
+            | Error::InvalidDate(_, _)
+            | Error::MissingField(_) => StatusCode::BAD_GATEWAY,
+            Error::StorePoisoned
+            | Error::Sqlite(_)
+            | Error::Join(_)
+            | Error::Csv(_)
+            | Error::Request(_)
+            | Error::Recorder(_)
+            | Error::Panic(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // Status Atlas answered with, when the error stems from one
+    pub fn upstream_status(&self) -> Option<u16> {
+        match *self {
+            Error::Unauthorized(_) => Some(401),
+            Error::Forbidden(_) => Some(403),
+            Error::NotFound(_) => Some(404),
+            Error::RateLimited(_, _) => Some(429),
+            Error::UpstreamUnavailable(status, _)
+            | Error::UnknownCode(status, _)
+            | Error::UnexpectedCode(status) => Some(status),
+            _ => None,
+        }
+    }
+
+    // Error body Atlas sent along with the failed response
+    pub fn atlas(&self) -> Option<&AtlasError> {
+        match self {
+            Error::Unauthorized(atlas)
+            | Error::Forbidden(atlas)
+            | Error::NotFound(atlas)
+            | Error::UnknownCode(_, atlas)
+            | Error::RateLimited(_, atlas)
+            | Error::UpstreamUnavailable(_, atlas) => Some(atlas),
+            _ => None,
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let atlas = self.atlas().cloned().unwrap_or_default();
+        ErrorBody {
+            kind: self.kind(),
+            error: self.message(),
+            upstream_status: self.upstream_status(),
+            error_code: atlas.error_code,
+            detail: atlas.detail,
+            reason: atlas.reason,
+            path: None,
+        }
+    }
+
+    // Human readable description, without any json around it
+    pub fn message(&self) -> String {
+        match *self {
+            Error::Forbidden(_) => "Status: Forbidden".to_string(),
+            Error::UnknownCode(status, _) => format!("Caught bad status code {status}"),
+            Error::Unauthorized(_) => "Status: Unauthorized".to_string(),
+            Error::NotFound(_) => "Status: Not found".to_string(),
+            Error::UnexpectedCode(status) => format!("Unexpected status code {status} received"),
+            Error::MissingHeader => "Missing expected response header".to_string(),
+            Error::Hyper(ref err) => err.to_string(),
+            Error::SerdeJson(ref err) => err.to_string(),
+            Error::Digest(ref err) => err.to_string(),
+            Error::InvalidHeaderValue(ref err) => err.to_string(),
+            Error::StoreDisabled => "No database configured".to_string(),
+            Error::StorePoisoned => "Database lock poisoned".to_string(),
+            Error::Sqlite(ref err) => err.to_string(),
+            Error::Join(ref err) => err.to_string(),
+            Error::Csv(ref err) => err.to_string(),
+            Error::InvalidColumn(ref column) => format!("Unknown column {column}"),
+            Error::InvalidAggregate(ref aggregate) => format!("Unknown aggregate {aggregate}"),
+            Error::InvalidInvoiceId(ref id) => {
+                format!("Invalid invoice {id}, expected pending, previous or a 24 character id")
             }
-            Error::Hyper(ref err) => write!(f, "{{\"error\": \"{err}\"}}"),
-            Error::SerdeJson(ref err) => write!(f, "{{\"error\": \"{err}\"}}"),
-            Error::Digest(ref err) => write!(f, "{{\"error\": \"{err}\"}}"),
-            Error::InvalidHeaderValue(ref err) => write!(f, "{{\"error\": \"{err}\"}}"),
+            Error::InvalidDate(ref value, ref err) => format!("Invalid date {value}: {err}"),
+            Error::InvalidQueryDate(ref value, ref err) => {
+                format!("Invalid date {value}: {err}, expected YYYY-MM-DD")
+            }
+            Error::RateLimited(_, _) => "Status: Too many requests".to_string(),
+            Error::UpstreamUnavailable(status, _) => format!("Atlas unavailable, status {status}"),
+            Error::Timeout => "Atlas request timed out".to_string(),
+            Error::MissingField(field) => format!("Atlas response is missing {field}"),
+            Error::Request(ref err) => format!("Invalid request: {err}"),
+            Error::Recorder(ref err) => err.to_string(),
+            Error::Panic(ref msg) => format!("Handler panicked: {msg}"),
         }
     }
+
+    // Machine readable kind returned in the error body
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Error::Unauthorized(_) => "upstream_unauthorized",
+            Error::Forbidden(_) => "upstream_forbidden",
+            Error::NotFound(_) => "upstream_not_found",
+            Error::RateLimited(_, _) => "upstream_rate_limited",
+            Error::UpstreamUnavailable(_, _) => "upstream_unavailable",
+            Error::Hyper(_) => "upstream_unreachable",
+            Error::Timeout => "upstream_timeout",
+            Error::SerdeJson(_) | Error::InvalidDate(_, _) | Error::MissingField(_) => {
+                "upstream_invalid_response"
+            }
+            Error::UnknownCode(_, _)
+            | Error::UnexpectedCode(_)
+            | Error::MissingHeader
+            | Error::Digest(_)
+            | Error::InvalidHeaderValue(_) => "upstream_error",
+            Error::StoreDisabled => "store_disabled",
+            Error::InvalidColumn(_)
+            | Error::InvalidAggregate(_)
+            | Error::InvalidInvoiceId(_)
+            | Error::InvalidQueryDate(_, _) => "invalid_parameter",
+            Error::StorePoisoned
+            | Error::Sqlite(_)
+            | Error::Join(_)
+            | Error::Csv(_)
+            | Error::Request(_)
+            | Error::Recorder(_)
+            | Error::Panic(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.body().fmt(f)
+    }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        let payload = self.to_string();
-        let body = body::boxed(body::Full::from(payload));
+        // The body itself is written by error_context once the request path is known
+        let mut response = (self.status(), [(CONTENT_TYPE, "application/json")]).into_response();
+        if let Error::RateLimited(Some(seconds), _) = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response.extensions_mut().insert(self.body());
+        response
+    }
+}
 
-        Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(body)
-            .unwrap()
+// Write the body of error responses with the request path added, and log them
+pub async fn error_context<B>(req: Request<B>, next: Next<B>) -> Response {
+    let path = req.uri().path().to_owned();
+    let mut response = next.run(req).await;
+
+    if let Some(mut error) = response.extensions_mut().remove::<ErrorBody>() {
+        error.path = Some(path);
+        let payload = error.to_string();
+        error.log("request failed");
+
+        response.headers_mut().remove(CONTENT_LENGTH);
+        *response.body_mut() = body::boxed(body::Full::from(payload));
+    }
+
+    response
+}
+
+// Turn a panicking handler into a 500, so one odd Atlas response cannot take the exporter down
+pub async fn catch_panic<B>(req: Request<B>, next: Next<B>) -> Response {
+    match AssertUnwindSafe(next.run(req)).catch_unwind().await {
+        Ok(response) => response,
+        Err(panic) => {
+            let msg = panic
+                .downcast_ref::<&str>()
+                .map(|msg| msg.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            Error::Panic(msg).into_response()
+        }
     }
 }
 
@@ -78,3 +363,108 @@ impl From<hyper::header::InvalidHeaderValue> for Error {
         Error::InvalidHeaderValue(err)
     }
 }
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::Sqlite(err)
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(err: tokio::task::JoinError) -> Error {
+        Error::Join(err)
+    }
+}
+
+impl From<hyper::http::Error> for Error {
+    fn from(err: hyper::http::Error) -> Error {
+        Error::Request(err)
+    }
+}
+
+impl From<metrics_exporter_prometheus::BuildError> for Error {
+    fn from(err: metrics_exporter_prometheus::BuildError) -> Error {
+        Error::Recorder(err)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Error {
+        Error::Csv(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_upstream_errors_to_status_and_kind() {
+        let atlas = AtlasError::default;
+        let json = serde_json::from_str::<u64>("{").unwrap_err();
+        let date = "2024-13-01".parse::<chrono::NaiveDate>().unwrap_err();
+        let cases = [
+            (
+                Error::Unauthorized(atlas()),
+                502,
+                "upstream_unauthorized",
+                Some(401),
+            ),
+            (
+                Error::Forbidden(atlas()),
+                502,
+                "upstream_forbidden",
+                Some(403),
+            ),
+            (
+                Error::NotFound(atlas()),
+                502,
+                "upstream_not_found",
+                Some(404),
+            ),
+            (
+                Error::RateLimited(Some(30), atlas()),
+                503,
+                "upstream_rate_limited",
+                Some(429),
+            ),
+            (
+                Error::UpstreamUnavailable(500, atlas()),
+                503,
+                "upstream_unavailable",
+                Some(500),
+            ),
+            (
+                Error::UpstreamUnavailable(503, atlas()),
+                503,
+                "upstream_unavailable",
+                Some(503),
+            ),
+            (Error::Timeout, 504, "upstream_timeout", None),
+            (
+                Error::SerdeJson(json),
+                502,
+                "upstream_invalid_response",
+                None,
+            ),
+            (
+                Error::InvalidQueryDate("2024-13-01".to_string(), date),
+                400,
+                "invalid_parameter",
+                None,
+            ),
+            (
+                Error::InvalidInvoiceId("../x".to_string()),
+                400,
+                "invalid_parameter",
+                None,
+            ),
+        ];
+
+        for (error, status, kind, upstream_status) in cases {
+            assert_eq!(error.status().as_u16(), status, "{error:?}");
+            assert_eq!(error.kind(), kind, "{error:?}");
+            assert_eq!(error.upstream_status(), upstream_status, "{error:?}");
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use chrono::{DateTime, Utc};
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use crate::error::Error as RestError;
+use crate::invoice::{Aggregate, Compressed, Data, LineItem, Tags};
+
+static DEFAULT_COLUMNS: &str =
+    "start_date,end_date,group_name,cluster_name,sku,unit,quantity,unit_price_dollars,total_dollars,tags";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    InvoiceId,
+    StartDate,
+    EndDate,
+    GroupName,
+    ClusterName,
+    Sku,
+    Project,
+    Unit,
+    Quantity,
+    UnitPriceDollars,
+    TotalCents,
+    TotalDollars,
+    // One column for the given tag key
+    Tag(String),
+    // Expands to one column per tag key found in the rows
+    Tags,
+}
+
+impl FromStr for Column {
+    type Err = RestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "invoice_id" => Ok(Column::InvoiceId),
+            "start_date" => Ok(Column::StartDate),
+            "end_date" => Ok(Column::EndDate),
+            "group_name" => Ok(Column::GroupName),
+            "cluster_name" => Ok(Column::ClusterName),
+            "sku" => Ok(Column::Sku),
+            "project" => Ok(Column::Project),
+            "unit" => Ok(Column::Unit),
+            "quantity" => Ok(Column::Quantity),
+            "unit_price_dollars" => Ok(Column::UnitPriceDollars),
+            "total_cents" => Ok(Column::TotalCents),
+            "total_dollars" => Ok(Column::TotalDollars),
+            "tags" => Ok(Column::Tags),
+            other => match other.strip_prefix("tag:") {
+                Some(key) if !key.is_empty() => Ok(Column::Tag(key.to_string())),
+                _ => Err(RestError::InvalidColumn(other.to_string())),
+            },
+        }
+    }
+}
+
+impl Column {
+    fn header(&self) -> String {
+        match self {
+            Column::InvoiceId => "invoice_id".to_string(),
+            Column::StartDate => "start_date".to_string(),
+            Column::EndDate => "end_date".to_string(),
+            Column::GroupName => "group_name".to_string(),
+            Column::ClusterName => "cluster_name".to_string(),
+            Column::Sku => "sku".to_string(),
+            Column::Project => "project".to_string(),
+            Column::Unit => "unit".to_string(),
+            Column::Quantity => "quantity".to_string(),
+            Column::UnitPriceDollars => "unit_price_dollars".to_string(),
+            Column::TotalCents => "total_cents".to_string(),
+            Column::TotalDollars => "total_dollars".to_string(),
+            Column::Tag(key) => format!("tag:{key}"),
+            Column::Tags => "tags".to_string(),
+        }
+    }
+
+    // Parse a comma separated list of columns, falling back to the defaults
+    pub fn parse_list(columns: Option<&str>) -> Result<Vec<Column>, RestError> {
+        columns
+            .unwrap_or(DEFAULT_COLUMNS)
+            .split(',')
+            .filter(|c| !c.trim().is_empty())
+            .map(Column::from_str)
+            .collect()
+    }
+}
+
+// A single csv row, built from either a line item or an aggregated record
+struct Row<'a> {
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    group_name: Option<&'a str>,
+    cluster_name: Option<&'a str>,
+    sku: &'a str,
+    project: Option<String>,
+    unit: &'a str,
+    quantity: f64,
+    unit_price_dollars: f64,
+    total_price_cents: u64,
+    tags: &'a Tags,
+}
+
+impl<'a> From<&'a LineItem> for Row<'a> {
+    fn from(item: &'a LineItem) -> Self {
+        Row {
+            start_date: item.start_date,
+            end_date: item.end_date,
+            group_name: item.group_name.as_deref(),
+            cluster_name: item.cluster_name.as_deref(),
+            sku: &item.sku,
+            project: item.project(),
+            unit: &item.unit,
+            quantity: item.quantity,
+            unit_price_dollars: item.unit_price_dollars,
+            total_price_cents: item.total_price_cents,
+            tags: &item.tags,
+        }
+    }
+}
+
+impl<'a> From<&'a Compressed> for Row<'a> {
+    fn from(item: &'a Compressed) -> Self {
+        Row {
+            start_date: item.start_date,
+            end_date: item.end_date,
+            group_name: item.group_name.as_deref(),
+            cluster_name: item.cluster_name.as_deref(),
+            sku: &item.sku,
+            project: item.project(),
+            unit: &item.unit,
+            quantity: item.quantity,
+            unit_price_dollars: item.unit_price_dollars,
+            total_price_cents: item.total_price_cents,
+            tags: &item.tags,
+        }
+    }
+}
+
+impl Row<'_> {
+    fn tag(&self, key: &str) -> String {
+        match self.tags {
+            Some(Some(map)) => map.get(key).map(|v| v.join(";")).unwrap_or_default(),
+            _ => String::new(),
+        }
+    }
+
+    fn field(&self, column: &Column, invoice_id: &str) -> String {
+        match column {
+            Column::InvoiceId => invoice_id.to_string(),
+            Column::StartDate => self.start_date.to_rfc3339(),
+            Column::EndDate => self.end_date.to_rfc3339(),
+            Column::GroupName => self.group_name.unwrap_or_default().to_string(),
+            Column::ClusterName => self.cluster_name.unwrap_or_default().to_string(),
+            Column::Sku => self.sku.to_string(),
+            Column::Project => self.project.clone().unwrap_or_default(),
+            Column::Unit => self.unit.to_string(),
+            Column::Quantity => self.quantity.to_string(),
+            Column::UnitPriceDollars => self.unit_price_dollars.to_string(),
+            Column::TotalCents => self.total_price_cents.to_string(),
+            Column::TotalDollars => format!("{:.2}", self.total_price_cents as f64 / 100.0),
+            Column::Tag(key) => self.tag(key),
+            Column::Tags => String::new(),
+        }
+    }
+}
+
+// Write line items, or aggregated records when aggregate is set, as csv
+pub fn to_csv(
+    data: &Data,
+    columns: &[Column],
+    aggregate: Option<Aggregate>,
+) -> Result<Vec<u8>, RestError> {
+    let aggregated: Vec<Compressed> = match aggregate {
+        Some(aggregate) => data.aggregate_sorted(aggregate),
+        None => Vec::new(),
+    };
+
+    let rows: Vec<Row> = match aggregate {
+        Some(_) => aggregated.iter().map(Row::from).collect(),
+        None => data.line_items.iter().map(Row::from).collect(),
+    };
+
+    // Expand the tags column into one column per tag key
+    let tag_keys: BTreeSet<&str> = rows
+        .iter()
+        .filter_map(|r| match r.tags {
+            Some(Some(map)) => Some(map.keys().map(|k| k.as_str())),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+
+    let columns: Vec<Column> = columns
+        .iter()
+        .flat_map(|c| match c {
+            Column::Tags => tag_keys
+                .iter()
+                .map(|k| Column::Tag(k.to_string()))
+                .collect(),
+            c => vec![c.clone()],
+        })
+        .collect();
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(columns.iter().map(|c| c.header()))?;
+    for row in &rows {
+        writer.write_record(columns.iter().map(|c| row.field(c, &data.id)))?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| RestError::Csv(e.into_error().into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invoice() -> Data {
+        serde_json::from_value(serde_json::json!({
+            "amountBilledCents": 223,
+            "amountPaidCents": 0,
+            "created": "2024-03-01T00:00:00Z",
+            "creditsCents": 0,
+            "endDate": "2024-04-01T00:00:00Z",
+            "id": "65e1a2b3c4d5e6f708091a2b",
+            "lineItems": [
+                {
+                    "clusterName": "c0",
+                    "created": "2024-03-02T00:00:00Z",
+                    "endDate": "2024-03-02T00:00:00Z",
+                    "quantity": 2.5,
+                    "groupName": "group",
+                    "sku": "DISK",
+                    "startDate": "2024-03-01T00:00:00Z",
+                    "tags": {"project": ["web"], "team": ["a", "b"]},
+                    "totalPriceCents": 25,
+                    "unit": "GB",
+                    "unitPriceDollars": 0.1,
+                },
+                {
+                    "clusterName": null,
+                    "created": "2024-03-03T00:00:00Z",
+                    "endDate": "2024-03-03T00:00:00Z",
+                    "quantity": 24.0,
+                    "groupName": "group",
+                    "sku": "INSTANCE",
+                    "startDate": "2024-03-02T00:00:00Z",
+                    "tags": {"env": ["prod"]},
+                    "totalPriceCents": 198,
+                    "unit": "HOURS",
+                    "unitPriceDollars": 0.0825,
+                },
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn csv(columns: Option<&str>) -> String {
+        let columns = Column::parse_list(columns).unwrap();
+        String::from_utf8(to_csv(&invoice(), &columns, None).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn writes_default_columns_with_one_column_per_tag() {
+        assert_eq!(
+            csv(None),
+            "start_date,end_date,group_name,cluster_name,sku,unit,quantity,unit_price_dollars,total_dollars,tag:env,tag:project,tag:team
+2024-03-01T00:00:00+00:00,2024-03-02T00:00:00+00:00,group,c0,DISK,GB,2.5,0.1,0.25,,web,a;b
+2024-03-02T00:00:00+00:00,2024-03-03T00:00:00+00:00,group,,INSTANCE,HOURS,24,0.0825,1.98,prod,,
+"
+        );
+    }
+
+    #[test]
+    fn writes_selected_columns() {
+        assert_eq!(
+            csv(Some("invoice_id, sku,tag:team,total_cents,")),
+            "invoice_id,sku,tag:team,total_cents
+65e1a2b3c4d5e6f708091a2b,DISK,a;b,25
+65e1a2b3c4d5e6f708091a2b,INSTANCE,,198
+"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_columns() {
+        for columns in ["sku,price", "tag:"] {
+            match Column::parse_list(Some(columns)) {
+                Err(RestError::InvalidColumn(_)) => {}
+                other => panic!("expected InvalidColumn for {columns}, got {other:?}"),
+            }
+        }
+    }
+}
@@ -1,47 +1,172 @@
 use axum::{
-    extract::{Extension, OriginalUri},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Extension, OriginalUri, Query},
+    http::header::{ACCEPT, CONTENT_DISPOSITION, CONTENT_TYPE},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
 use clap::{crate_description, crate_name, crate_version};
-use metrics_exporter_prometheus::PrometheusHandle;
+use serde::Deserialize;
 use serde_json::json;
 use serde_json::Value;
 
-use crate::error::Error as RestError;
+use crate::check;
+use crate::error::{Error as RestError, ErrorBody};
+use crate::export::{to_csv, Column};
+use crate::invoice::{parse_day, Filter, InvoiceSelector, RawFilter};
+use crate::metrics::{forget_expired, MetricsHandle};
+use crate::openmetrics;
 use crate::State;
 
-// This is required in order to get the method from the request
-#[derive(Debug)]
-pub struct RequestMethod(pub hyper::Method);
-
+// Prometheus text by default, OpenMetrics when the scraper asks for it. With a background refresh
+// interval the last refresh is served as is, otherwise every scrape fetches the invoice.
 pub async fn metrics(
-    Extension(recorder_handle): Extension<PrometheusHandle>,
+    headers: HeaderMap,
+    Extension(recorder_handle): Extension<MetricsHandle>,
     Extension(state): Extension<State>,
-) -> Result<String, RestError> {
-    log::info!("{{\"fn\": \"metrics\", \"method\":\"get\"}}");
-    state.get_metrics().await?;
-    Ok(recorder_handle.render())
+) -> Result<Response, RestError> {
+    tracing::info!(handler = "metrics", method = "get");
+    if state.refresh_interval.is_none() {
+        state.get_metrics().await?;
+    }
+    let rendered = recorder_handle.render();
+    forget_expired(&rendered);
+
+    match openmetrics::accepts(headers.get(ACCEPT)) {
+        true => {
+            let body = openmetrics::render(&rendered);
+            Ok(([(CONTENT_TYPE, openmetrics::CONTENT_TYPE)], body).into_response())
+        }
+        false => Ok(rendered.into_response()),
+    }
+}
+
+pub async fn daily(Extension(state): Extension<State>) -> Result<Json<Value>, RestError> {
+    tracing::info!(handler = "daily", method = "get");
+    let daily = state.get_daily().await?;
+    Ok(Json(json!(daily)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HistoryParams {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+pub async fn history(
+    Extension(state): Extension<State>,
+    Query(params): Query<HistoryParams>,
+) -> Result<Json<Value>, RestError> {
+    tracing::info!(handler = "history", method = "get");
+    let from = params.from.as_deref().map(parse_day).transpose()?;
+    let to = params.to.as_deref().map(parse_day).transpose()?;
+    let history = state.get_history(from, to).await?;
+    Ok(Json(json!(history)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LineItemParams {
+    invoice: Option<String>,
+    aggregate: Option<String>,
+    #[serde(flatten)]
+    filter: RawFilter,
+}
+
+pub async fn line_items(
+    Extension(state): Extension<State>,
+    Query(params): Query<LineItemParams>,
+) -> Result<Json<Value>, RestError> {
+    tracing::info!(handler = "line_items", method = "get");
+    let selector: InvoiceSelector = params.invoice.as_deref().unwrap_or_default().parse()?;
+    // Parsed here rather than by serde, so bad values get the usual error body
+    let aggregate = params.aggregate.as_deref().map(str::parse).transpose()?;
+    let filter = Filter::try_from(params.filter)?;
+    let items = state
+        .get_line_items(&selector, &filter, aggregate.unwrap_or_default())
+        .await?;
+    Ok(Json(json!(items)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExportParams {
+    invoice: Option<String>,
+    aggregate: Option<String>,
+    columns: Option<String>,
+    #[serde(flatten)]
+    filter: RawFilter,
+}
+
+pub async fn export_csv(
+    Extension(state): Extension<State>,
+    Query(params): Query<ExportParams>,
+) -> Result<impl IntoResponse, RestError> {
+    tracing::info!(handler = "export_csv", method = "get");
+    let selector: InvoiceSelector = params.invoice.as_deref().unwrap_or_default().parse()?;
+    let aggregate = params.aggregate.as_deref().map(str::parse).transpose()?;
+    let filter = Filter::try_from(params.filter)?;
+    let columns = Column::parse_list(params.columns.as_deref())?;
+    let data = state.get_selected_invoice(&selector).await?;
+    let csv = to_csv(&data.filter(&filter), &columns, aggregate)?;
+
+    Ok((
+        [
+            (CONTENT_TYPE, "text/csv".to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"atlas-billing-{}.csv\"", data.id),
+            ),
+        ],
+        csv,
+    ))
+}
+
+pub async fn status(Extension(state): Extension<State>) -> impl IntoResponse {
+    tracing::info!(handler = "status", method = "get");
+    let report = check::run(&state).await;
+    let code = match report.ok {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (code, Json(json!(report)))
 }
 
 pub async fn health() -> Json<Value> {
-    log::info!("{{\"fn\": \"health\", \"method\":\"get\"}}");
+    tracing::info!(handler = "health", method = "get");
     Json(json!({ "msg": "Healthy"}))
 }
 
+pub async fn ready(Extension(state): Extension<State>) -> impl IntoResponse {
+    tracing::info!(handler = "ready", method = "get");
+    match state.readiness() {
+        Ok(refresh) => (
+            StatusCode::OK,
+            Json(json!({ "msg": "Ready", "refresh": refresh })),
+        ),
+        Err(refresh) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "msg": "Not ready", "refresh": refresh })),
+        ),
+    }
+}
+
 pub async fn root() -> Json<Value> {
-    log::info!("{{\"fn\": \"root\", \"method\":\"get\"}}");
+    tracing::info!(handler = "root", method = "get");
     Json(
         json!({ "version": crate_version!(), "name": crate_name!(), "description": crate_description!()}),
     )
 }
 
 pub async fn help() -> Json<Value> {
-    log::info!("{{\"fn\": \"help\", \"method\":\"get\"}}");
+    tracing::info!(handler = "help", method = "get");
     let payload = json!({"paths": {
             "/health": "Get the health of the api",
+            "/ready": "Check whether billing data was fetched recently",
             "/metrics": "Get Elastic Billing Metrics",
+            "/daily": "Get daily cost per sku for the current invoice",
+            "/api/v1/line-items": "Get aggregated line items, filtered by ?invoice=pending|previous|<id>&aggregate=total|rate|daily&cluster=&project=&sku=&tag=key:value&from=&to=",
+            "/export/csv": "Get line items as csv, accepts the line item filters plus ?columns=sku,total_dollars,tags",
+            "/history": "Get monthly cost per sku from the backfilled database, filtered by ?from=&to= dates",
+            "/status": "Check Atlas reachability, credentials, org access and invoice permissions",
             "/help": "Show this help message"
         }
     });
@@ -49,14 +174,23 @@ pub async fn help() -> Json<Value> {
 }
 
 pub async fn handler_404(OriginalUri(original_uri): OriginalUri) -> impl IntoResponse {
-    let parts = original_uri.into_parts();
-    let path_and_query = parts.path_and_query.expect("Missing post path and query");
-    log::info!(
-        "{{\"fn\": \"handler_404\", \"method\":\"get\", \"path\":\"{}\"}}",
-        path_and_query
+    let path_and_query = original_uri
+        .path_and_query()
+        .map_or(original_uri.path(), |p| p.as_str());
+    tracing::info!(
+        handler = "handler_404",
+        method = "get",
+        path = path_and_query
     );
+    let error = ErrorBody {
+        kind: "not_found",
+        error: "HTTP 404 Not Found".to_string(),
+        path: Some(original_uri.path().to_string()),
+        ..ErrorBody::default()
+    };
     (
         StatusCode::NOT_FOUND,
-        "{\"error_code\": 404, \"message\": \"HTTP 404 Not Found\"}",
+        [(CONTENT_TYPE, "application/json")],
+        error.to_string(),
     )
 }
@@ -0,0 +1,804 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::error::Error as RestError;
+use crate::units::{Unit, UnknownUnit};
+
+// Fallback for line items that do not cover a positive time range
+const HOURS_PER_LINE_ITEM: f64 = 24.0;
+
+pub type Tags = Option<Option<HashMap<String, Vec<String>>>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Data {
+    pub amount_billed_cents: u64,
+    pub amount_paid_cents: u64,
+    pub created: DateTime<Utc>,
+    pub credits_cents: u64,
+    pub end_date: DateTime<Utc>,
+    pub id: String,
+    pub line_items: Vec<LineItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LineItem {
+    pub cluster_name: Option<String>,
+    pub created: DateTime<Utc>,
+    pub end_date: DateTime<Utc>,
+    pub quantity: f64,
+    pub group_name: Option<String>,
+    pub sku: String,
+    pub start_date: DateTime<Utc>,
+    pub tags: Tags,
+    pub total_price_cents: u64,
+    pub unit: String,
+    pub unit_price_dollars: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Compressed {
+    pub cluster_name: Option<String>,
+    pub quantity: f64,
+    pub group_name: Option<String>,
+    pub sku: String,
+    pub total_price_cents: u64,
+    pub unit: String,
+    pub unit_price_dollars: f64,
+    pub tags: Tags,
+    pub end_date: DateTime<Utc>,
+    pub start_date: DateTime<Utc>,
+}
+
+// Aggregated records of one invoice, as exported through metrics
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub invoice_id: String,
+    pub totals: Vec<Compressed>,
+    pub rates: Vec<Compressed>,
+    pub daily: Vec<Compressed>,
+}
+
+// One value of a billing series, whatever it is exported through
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub name: &'static str,
+    pub value: f64,
+    pub labels: Vec<(&'static str, String)>,
+}
+
+// Invoice as returned by Atlas, before the dates are parsed
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RawData {
+    amount_billed_cents: u64,
+    amount_paid_cents: u64,
+    created: String,
+    credits_cents: u64,
+    end_date: String,
+    id: String,
+    line_items: Vec<RawLineItem>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RawLineItem {
+    cluster_name: Option<String>,
+    created: String,
+    end_date: String,
+    quantity: f64,
+    group_name: Option<String>,
+    sku: String,
+    start_date: String,
+    tags: Tags,
+    total_price_cents: u64,
+    unit: String,
+    unit_price_dollars: f64,
+}
+
+// Which invoice to read line items from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvoiceSelector {
+    // Previous invoice on the first day of the month, pending invoice otherwise
+    Current,
+    Pending,
+    Previous,
+    Id(String),
+}
+
+impl FromStr for InvoiceSelector {
+    type Err = RestError;
+
+    // Ids end up in the signed Atlas path, so only the 24 hex characters Atlas uses are taken
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" | "current" => Ok(InvoiceSelector::Current),
+            "pending" => Ok(InvoiceSelector::Pending),
+            "previous" => Ok(InvoiceSelector::Previous),
+            id if id.len() == 24 && id.chars().all(|c| c.is_ascii_hexdigit()) => {
+                Ok(InvoiceSelector::Id(id.to_string()))
+            }
+            other => Err(RestError::InvalidInvoiceId(other.to_string())),
+        }
+    }
+}
+
+// How line items are summed up into Compressed records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Aggregate {
+    #[default]
+    Total,
+    Rate,
+    Daily,
+}
+
+impl FromStr for Aggregate {
+    type Err = RestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "total" => Ok(Aggregate::Total),
+            "rate" => Ok(Aggregate::Rate),
+            "daily" => Ok(Aggregate::Daily),
+            other => Err(RestError::InvalidAggregate(other.to_string())),
+        }
+    }
+}
+
+// Restricts line items by cluster, project, sku, tag and day of usage
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub cluster: Option<String>,
+    pub project: Option<String>,
+    pub sku: Option<String>,
+    // Either key or key:value
+    pub tag: Option<String>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+// Filter as given in the query string, before the dates are parsed
+#[derive(Deserialize, Debug, Default)]
+pub struct RawFilter {
+    cluster: Option<String>,
+    project: Option<String>,
+    sku: Option<String>,
+    tag: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl TryFrom<RawFilter> for Filter {
+    type Error = RestError;
+
+    fn try_from(raw: RawFilter) -> Result<Self, Self::Error> {
+        Ok(Filter {
+            cluster: raw.cluster,
+            project: raw.project,
+            sku: raw.sku,
+            tag: raw.tag,
+            from: raw.from.as_deref().map(parse_day).transpose()?,
+            to: raw.to.as_deref().map(parse_day).transpose()?,
+        })
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, item: &LineItem) -> bool {
+        let day = item.day();
+
+        self.cluster
+            .as_ref()
+            .is_none_or(|c| item.cluster_name.as_ref() == Some(c))
+            && self
+                .project
+                .as_ref()
+                .is_none_or(|p| item.project().as_ref() == Some(p))
+            && self.sku.as_ref().is_none_or(|s| &item.sku == s)
+            && self.tag.as_ref().is_none_or(|t| item.has_tag(t))
+            && self.from.is_none_or(|from| day >= from)
+            && self.to.is_none_or(|to| day <= to)
+    }
+}
+
+// First value of the project tag, used as a label
+fn project(tags: &Tags) -> Option<String> {
+    match tags {
+        Some(Some(map)) => map.get("project").and_then(|v| v.first()).cloned(),
+        _ => None,
+    }
+}
+
+fn parse_date(value: &str) -> Result<DateTime<Utc>, RestError> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|e| RestError::InvalidDate(value.to_string(), e))
+}
+
+// Day given as YYYY-MM-DD in a query
+pub fn parse_day(value: &str) -> Result<NaiveDate, RestError> {
+    value
+        .parse()
+        .map_err(|e| RestError::InvalidQueryDate(value.to_string(), e))
+}
+
+impl TryFrom<RawData> for Data {
+    type Error = RestError;
+
+    fn try_from(raw: RawData) -> Result<Self, Self::Error> {
+        Ok(Data {
+            amount_billed_cents: raw.amount_billed_cents,
+            amount_paid_cents: raw.amount_paid_cents,
+            created: parse_date(&raw.created)?,
+            credits_cents: raw.credits_cents,
+            end_date: parse_date(&raw.end_date)?,
+            id: raw.id,
+            line_items: raw
+                .line_items
+                .into_iter()
+                .map(LineItem::try_from)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl TryFrom<RawLineItem> for LineItem {
+    type Error = RestError;
+
+    fn try_from(raw: RawLineItem) -> Result<Self, Self::Error> {
+        Ok(LineItem {
+            cluster_name: raw.cluster_name,
+            created: parse_date(&raw.created)?,
+            end_date: parse_date(&raw.end_date)?,
+            quantity: raw.quantity,
+            group_name: raw.group_name,
+            sku: raw.sku,
+            start_date: parse_date(&raw.start_date)?,
+            tags: raw.tags,
+            total_price_cents: raw.total_price_cents,
+            unit: raw.unit,
+            unit_price_dollars: raw.unit_price_dollars,
+        })
+    }
+}
+
+impl From<&Data> for Snapshot {
+    fn from(data: &Data) -> Self {
+        Snapshot {
+            invoice_id: data.id.clone(),
+            totals: data.aggregate_sorted(Aggregate::Total),
+            rates: data.aggregate_sorted(Aggregate::Rate),
+            daily: data.aggregate_sorted(Aggregate::Daily),
+        }
+    }
+}
+
+impl Snapshot {
+    // Every billing series of the snapshot. Unit prices in a unit the exporter does not know are
+    // left out, unknown_units lists them. Their cost rate does not depend on the unit and is kept.
+    pub fn samples(&self) -> Vec<Sample> {
+        let mut samples = Vec::new();
+
+        for value in &self.totals {
+            samples.push(Sample {
+                name: "atlas_billing_item_cents_total",
+                value: value.total_price_cents as f64,
+                labels: value.labels(),
+            });
+        }
+
+        for value in &self.rates {
+            let mut labels = value.labels();
+            samples.push(Sample {
+                name: "atlas_billing_item_cents_rate",
+                value: value.rate(),
+                labels: labels.clone(),
+            });
+
+            let unit = match value.unit() {
+                Ok(unit) => unit,
+                Err(_) => continue,
+            };
+            labels.push(("unit", unit.normalized().to_string()));
+            samples.push(Sample {
+                name: "atlas_billing_item_unit_price_cents",
+                value: value.unit_price_cents(unit),
+                labels,
+            });
+        }
+
+        for value in &self.daily {
+            let mut labels = value.labels();
+            labels.push(("date", value.day().to_string()));
+            samples.push(Sample {
+                name: "atlas_billing_item_cents_daily",
+                value: value.total_price_cents as f64,
+                labels,
+            });
+        }
+
+        samples
+    }
+
+    // Rate records without a unit price sample, because Atlas reported a unit we do not know
+    pub fn unknown_units(&self) -> Vec<(&Compressed, UnknownUnit)> {
+        self.rates
+            .iter()
+            .filter_map(|value| value.unit().err().map(|e| (value, e)))
+            .collect()
+    }
+}
+
+impl Data {
+    // Most recent end_date across all line items
+    pub fn latest_end_date(&self) -> Option<DateTime<Utc>> {
+        self.line_items.iter().map(|i| i.end_date).max()
+    }
+
+    // Line items that ended within the given range, bounds are inclusive
+    pub fn between(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> impl Iterator<Item = &LineItem> {
+        self.line_items.iter().filter(move |i| {
+            from.is_none_or(|from| i.end_date >= from) && to.is_none_or(|to| i.end_date <= to)
+        })
+    }
+
+    // Copy of the invoice holding only the line items matching the filter
+    pub fn filter(&self, filter: &Filter) -> Data {
+        Data {
+            line_items: self
+                .line_items
+                .iter()
+                .filter(|i| filter.matches(i))
+                .cloned()
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    pub fn aggregate(&self, aggregate: Aggregate) -> HashMap<String, Compressed> {
+        match aggregate {
+            Aggregate::Total => self.totals(),
+            Aggregate::Rate => self.rates(),
+            Aggregate::Daily => self.daily(),
+        }
+    }
+
+    // Aggregated records ordered by date, cluster and sku
+    pub fn aggregate_sorted(&self, aggregate: Aggregate) -> Vec<Compressed> {
+        let mut items: Vec<Compressed> = self.aggregate(aggregate).into_values().collect();
+        items.sort_by(|a, b| {
+            (a.start_date, &a.cluster_name, &a.sku).cmp(&(b.start_date, &b.cluster_name, &b.sku))
+        });
+        items
+    }
+
+    // Sum up line items per cluster and sku across the whole invoice
+    pub fn totals(&self) -> HashMap<String, Compressed> {
+        let mut map_total: HashMap<String, Compressed> = HashMap::new();
+
+        for item in &self.line_items {
+            let name = item.name();
+
+            tracing::debug!("Working on {} from {}", name, item.end_date);
+
+            match map_total.get_mut(&name) {
+                Some(k) => {
+                    tracing::debug!("Found existing {} in map_total, adding up total", &name);
+
+                    // Atlas prices sku's per region, so we need to get the sum
+                    k.add(item);
+                }
+                None => {
+                    tracing::debug!("Did not find existing {} in map_total", &name);
+                    map_total.insert(name, item.compress());
+                }
+            }
+        }
+
+        map_total
+    }
+
+    // Sum up the line items of the most recent day per cluster and sku
+    pub fn rates(&self) -> HashMap<String, Compressed> {
+        let mut map_rate: HashMap<String, Compressed> = HashMap::new();
+
+        // Get most recent metric date across all metrics
+        let current_date = match self.latest_end_date() {
+            Some(date) => date,
+            None => return map_rate,
+        };
+
+        // Only include metric if the end_date is today
+        for item in self.between(Some(current_date), None) {
+            let name = item.name();
+
+            match map_rate.get_mut(&name) {
+                Some(k) => {
+                    tracing::debug!("Found existing {} in map_rate", &name);
+                    // This metric has the same start date, indicating a SKU present in multiple regions
+                    // Therefore, get the sum of all
+                    // Atlas prices sku's per region, so we need to get the sum
+                    k.add(item);
+                    tracing::debug!("{} is already set in map_rate, and has the same end_date. Adding up total price to get {}", &name, k.total_price_cents);
+                }
+                None => {
+                    tracing::debug!("Did not find existing {} in map_rate", &name);
+                    map_rate.insert(name, item.compress());
+                }
+            }
+        }
+
+        map_rate
+    }
+
+    // Sum up line items per cluster, sku and day
+    pub fn daily(&self) -> HashMap<String, Compressed> {
+        let mut map_daily: HashMap<String, Compressed> = HashMap::new();
+
+        for item in &self.line_items {
+            let name = format!("{}_{}", item.name(), item.day());
+
+            match map_daily.get_mut(&name) {
+                Some(k) => {
+                    tracing::debug!("Found existing {} in map_daily, adding up total", &name);
+
+                    // Atlas prices sku's per region, so we need to get the sum
+                    k.add(item);
+                }
+                None => {
+                    tracing::debug!("Did not find existing {} in map_daily", &name);
+                    map_daily.insert(name, item.compress());
+                }
+            }
+        }
+
+        map_daily
+    }
+}
+
+impl LineItem {
+    // Name used to group line items, as Atlas reports one item per region
+    fn name(&self) -> String {
+        match &self.cluster_name {
+            Some(e) => format!("{}_{}", e, self.sku),
+            None => self.sku.to_string(),
+        }
+    }
+
+    // Day of usage, taken from the start_date timestamp
+    pub fn day(&self) -> NaiveDate {
+        self.start_date.date_naive()
+    }
+
+    pub fn project(&self) -> Option<String> {
+        project(&self.tags)
+    }
+
+    // Check for a tag given as key or key:value
+    pub fn has_tag(&self, tag: &str) -> bool {
+        let map = match &self.tags {
+            Some(Some(map)) => map,
+            _ => return false,
+        };
+
+        match tag.split_once(':') {
+            Some((key, value)) => map
+                .get(key)
+                .is_some_and(|values| values.iter().any(|v| v == value)),
+            None => map.contains_key(tag),
+        }
+    }
+
+    fn compress(&self) -> Compressed {
+        Compressed {
+            cluster_name: self.cluster_name.clone(),
+            quantity: self.quantity,
+            sku: self.sku.clone(),
+            group_name: self.group_name.clone(),
+            total_price_cents: self.total_price_cents,
+            unit: self.unit.clone(),
+            unit_price_dollars: self.unit_price_dollars,
+            tags: self.tags.clone(),
+            start_date: self.start_date,
+            end_date: self.end_date,
+        }
+    }
+}
+
+impl Compressed {
+    // Add up another line item, widening the date range to cover both
+    fn add(&mut self, item: &LineItem) {
+        self.total_price_cents += item.total_price_cents;
+        self.quantity += item.quantity;
+        self.start_date = self.start_date.min(item.start_date);
+        self.end_date = self.end_date.max(item.end_date);
+    }
+
+    // Day of usage, taken from the start_date timestamp
+    pub fn day(&self) -> NaiveDate {
+        self.start_date.date_naive()
+    }
+
+    // Hours of usage covered by this record
+    pub fn hours(&self) -> f64 {
+        let hours = (self.end_date - self.start_date).num_seconds() as f64 / 3600.0;
+        match hours > 0.0 {
+            true => hours,
+            false => HOURS_PER_LINE_ITEM,
+        }
+    }
+
+    pub fn unit(&self) -> Result<Unit, UnknownUnit> {
+        self.unit.parse()
+    }
+
+    // Cost rate in cents per hour over the period covered by the line items
+    pub fn rate(&self) -> f64 {
+        self.total_price_cents as f64 / self.hours()
+    }
+
+    // Average price in cents per normalized unit, weighted across regions
+    pub fn unit_price_cents(&self, unit: Unit) -> f64 {
+        let price = match self.quantity > 0.0 {
+            true => self.total_price_cents as f64 / self.quantity,
+            false => self.unit_price_dollars * 100.0,
+        };
+        price * unit.price_factor()
+    }
+
+    pub fn project(&self) -> Option<String> {
+        project(&self.tags)
+    }
+
+    pub fn labels(&self) -> Vec<(&'static str, String)> {
+        vec![
+            (
+                "cluster_name",
+                self.cluster_name.clone().unwrap_or_default(),
+            ),
+            ("group_name", self.group_name.clone().unwrap_or_default()),
+            ("sku", self.sku.clone()),
+            ("project", self.project().unwrap_or_default()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> DateTime<Utc> {
+        parse_date(value).unwrap()
+    }
+
+    // One region's line item of a day, starting and ending at midnight
+    fn item(cluster: &str, sku: &str, start: &str, end: &str, cents: u64) -> LineItem {
+        LineItem {
+            cluster_name: Some(cluster.to_string()),
+            created: date(end),
+            end_date: date(end),
+            quantity: cents as f64,
+            group_name: Some("group".to_string()),
+            sku: sku.to_string(),
+            start_date: date(start),
+            tags: None,
+            total_price_cents: cents,
+            unit: "GB".to_string(),
+            unit_price_dollars: 0.01,
+        }
+    }
+
+    fn data(line_items: Vec<LineItem>) -> Data {
+        Data {
+            amount_billed_cents: 0,
+            amount_paid_cents: 0,
+            created: date("2024-03-01T00:00:00Z"),
+            credits_cents: 0,
+            end_date: date("2024-04-01T00:00:00Z"),
+            id: "65e1a2b3c4d5e6f708091a2b".to_string(),
+            line_items,
+        }
+    }
+
+    const DAY1: &str = "2024-03-01T00:00:00Z";
+    const DAY2: &str = "2024-03-02T00:00:00Z";
+    const DAY3: &str = "2024-03-03T00:00:00Z";
+
+    #[test]
+    fn sums_regions_and_days_in_totals() {
+        let totals = data(vec![
+            item("c0", "DISK", DAY1, DAY2, 10),
+            item("c0", "DISK", DAY1, DAY2, 5),
+            item("c0", "DISK", DAY2, DAY3, 7),
+            item("c0", "INSTANCE", DAY1, DAY2, 100),
+        ])
+        .totals();
+
+        assert_eq!(totals.len(), 2);
+        let disk = &totals["c0_DISK"];
+        assert_eq!(disk.total_price_cents, 22);
+        assert_eq!(disk.quantity, 22.0);
+        assert_eq!(disk.start_date, date(DAY1));
+        assert_eq!(disk.end_date, date(DAY3));
+        assert_eq!(totals["c0_INSTANCE"].total_price_cents, 100);
+    }
+
+    #[test]
+    fn keeps_only_the_latest_end_date_in_rates() {
+        let rates = data(vec![
+            item("c0", "DISK", DAY1, DAY2, 10),
+            item("c0", "DISK", DAY2, DAY3, 7),
+            item("c0", "DISK", DAY2, DAY3, 3),
+            item("c1", "DISK", DAY1, DAY2, 100),
+        ])
+        .rates();
+
+        assert_eq!(rates.len(), 1);
+        let disk = &rates["c0_DISK"];
+        assert_eq!(disk.total_price_cents, 10);
+        assert_eq!(disk.start_date, date(DAY2));
+        assert_eq!(disk.rate(), 10.0 / 24.0);
+    }
+
+    #[test]
+    fn keys_daily_on_the_start_day() {
+        let daily = data(vec![
+            item("c0", "DISK", "2024-03-01T12:00:00Z", DAY2, 10),
+            item("c0", "DISK", DAY1, "2024-03-01T12:00:00Z", 5),
+            item("c0", "DISK", DAY2, DAY3, 7),
+        ])
+        .daily();
+
+        assert_eq!(daily.len(), 2);
+        assert_eq!(daily["c0_DISK_2024-03-01"].total_price_cents, 15);
+        assert_eq!(daily["c0_DISK_2024-03-02"].total_price_cents, 7);
+        assert_eq!(daily["c0_DISK_2024-03-01"].day(), date(DAY1).date_naive());
+    }
+
+    fn raw(line_item_start: &str) -> RawData {
+        serde_json::from_value(serde_json::json!({
+            "amountBilledCents": 0,
+            "amountPaidCents": 0,
+            "created": DAY1,
+            "creditsCents": 0,
+            "endDate": "2024-04-01T00:00:00Z",
+            "id": "65e1a2b3c4d5e6f708091a2b",
+            "lineItems": [{
+                "clusterName": "c0",
+                "created": DAY2,
+                "endDate": DAY2,
+                "quantity": 1.0,
+                "groupName": "group",
+                "sku": "DISK",
+                "startDate": line_item_start,
+                "totalPriceCents": 10,
+                "unit": "GB",
+                "unitPriceDollars": 0.1,
+            }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_raw_dates() {
+        let data = Data::try_from(raw("2024-03-01T00:00:00+02:00")).unwrap();
+        assert_eq!(data.created, date(DAY1));
+        assert_eq!(data.line_items[0].start_date, date("2024-02-29T22:00:00Z"));
+        assert_eq!(data.line_items[0].tags, None);
+    }
+
+    #[test]
+    fn rejects_malformed_timestamps() {
+        match Data::try_from(raw("2024-03-01")) {
+            Err(RestError::InvalidDate(value, _)) => assert_eq!(value, "2024-03-01"),
+            other => panic!("expected InvalidDate, got {other:?}"),
+        }
+    }
+
+    fn tagged(mut item: LineItem, tags: &[(&str, &str)]) -> LineItem {
+        let map = tags
+            .iter()
+            .map(|(key, value)| (key.to_string(), vec![value.to_string()]))
+            .collect();
+        item.tags = Some(Some(map));
+        item
+    }
+
+    fn filtered(filter: RawFilter) -> Vec<(String, NaiveDate)> {
+        let invoice = data(vec![
+            tagged(item("c0", "DISK", DAY1, DAY2, 10), &[("project", "web")]),
+            tagged(item("c0", "DISK", DAY2, DAY3, 7), &[("project", "api")]),
+            tagged(
+                item("c1", "DISK", DAY3, "2024-03-04T00:00:00Z", 5),
+                &[("env", "prod")],
+            ),
+        ]);
+        let filter = Filter::try_from(filter).unwrap();
+        invoice
+            .filter(&filter)
+            .line_items
+            .iter()
+            .map(|i| (i.name(), i.day()))
+            .collect()
+    }
+
+    fn day(value: &str) -> NaiveDate {
+        parse_day(value).unwrap()
+    }
+
+    #[test]
+    fn filters_on_inclusive_days() {
+        let items = filtered(RawFilter {
+            from: Some("2024-03-02".to_string()),
+            to: Some("2024-03-02".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(items, vec![("c0_DISK".to_string(), day("2024-03-02"))]);
+
+        let items = filtered(RawFilter {
+            from: Some("2024-03-02".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(items.len(), 2);
+    }
+
+    #[test]
+    fn filters_on_tag_key_or_key_value() {
+        let items = filtered(RawFilter {
+            tag: Some("project:api".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(items, vec![("c0_DISK".to_string(), day("2024-03-02"))]);
+
+        let items = filtered(RawFilter {
+            tag: Some("project".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(items.len(), 2);
+
+        let items = filtered(RawFilter {
+            tag: Some("env:dev".to_string()),
+            ..Default::default()
+        });
+        assert!(items.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_query_days() {
+        let filter = RawFilter {
+            from: Some("03/01/2024".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Filter::try_from(filter),
+            Err(RestError::InvalidQueryDate(..))
+        ));
+    }
+
+    #[test]
+    fn selects_invoices_by_keyword_or_hex_id() {
+        let select = |value: &str| value.parse::<InvoiceSelector>();
+        assert_eq!(select("").unwrap(), InvoiceSelector::Current);
+        assert_eq!(select("pending").unwrap(), InvoiceSelector::Pending);
+        assert_eq!(select("previous").unwrap(), InvoiceSelector::Previous);
+        assert_eq!(
+            select("65e1a2b3c4d5e6f708091A2B").unwrap(),
+            InvoiceSelector::Id("65e1a2b3c4d5e6f708091A2B".to_string())
+        );
+        for id in [
+            "../../groups",
+            "65e1a2b3c4d5e6f708091a2",
+            "65e1a2b3c4d5e6f708091a2g",
+        ] {
+            assert!(matches!(select(id), Err(RestError::InvalidInvoiceId(_))));
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::error::Error;
+use std::io::{self, IsTerminal, Write};
+use std::sync::RwLock;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+
+use crate::telemetry::{self, Otlp};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const REDACTED: &str = "[REDACTED]";
+// Shorter values would scrub ordinary words out of every line, so they are refused as
+// credentials. Atlas keys and real credentials are far longer than this.
+const MIN_SECRET_LEN: usize = 6;
+
+// Values that must never show up in a log line, such as the API keys and accepted credentials
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+// Register a value to be scrubbed from every log line written from now on. Fails for values too
+// short to be redacted without mangling the rest of the line.
+pub fn redact(secret: &str) -> BoxResult<()> {
+    if secret.is_empty() {
+        return Ok(());
+    }
+    if secret.chars().count() < MIN_SECRET_LEN {
+        return Err(format!(
+            "credentials must be at least {MIN_SECRET_LEN} characters long to be kept out of the logs"
+        )
+        .into());
+    }
+
+    // JSON and Debug output escape quotes and backslashes, so those forms are scrubbed as well
+    let json = serde_json::to_string(secret)?;
+    let forms = [
+        secret.to_string(),
+        json[1..json.len() - 1].to_string(),
+        secret.escape_debug().to_string(),
+    ];
+
+    if let Ok(mut secrets) = SECRETS.write() {
+        for form in forms {
+            if !secrets.contains(&form) {
+                secrets.push(form);
+            }
+        }
+        // Longest first, so a secret containing another one is not left half visible
+        secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+    }
+    Ok(())
+}
+
+fn scrub(line: &str) -> String {
+    let secrets = match SECRETS.read() {
+        Ok(secrets) => secrets,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    secrets.iter().fold(line.to_string(), |line, secret| {
+        line.replace(secret, REDACTED)
+    })
+}
+
+// Writes to stdout with registered secrets scrubbed. The formatter hands over each event in a
+// single write, so a secret is never split across two calls.
+struct RedactingWriter;
+
+impl Write for RedactingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = scrub(&String::from_utf8_lossy(buf));
+        io::stdout().lock().write_all(line.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+// Install the global subscriber. The level takes EnvFilter directives, such as
+// "info,hyper=warn". Closing spans are logged too, carrying their recorded fields and timings.
+// With otlp set, the exporter's spans are also sent to a collector, whatever the log level, but
+// no events.
+pub fn init(format: &str, level: &str, otlp: Option<&Otlp>) -> BoxResult<()> {
+    let filter = EnvFilter::try_new(level)?;
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(io::stdout().is_terminal())
+        .with_writer(|| RedactingWriter);
+    let fmt = match format {
+        "text" => fmt.boxed(),
+        _ => fmt.json().flatten_event(true).boxed(),
+    };
+
+    let otel = match otlp {
+        Some(otlp) => {
+            // Only our own spans at any level. Their fields are fixed, while events carry free form
+            // messages and errors that would reach the collector without going through scrub.
+            let spans = filter_fn(|meta| {
+                meta.is_span() && meta.target().starts_with(env!("CARGO_CRATE_NAME"))
+            });
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(telemetry::tracer(otlp)?)
+                    .with_filter(spans),
+            )
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(fmt.with_filter(filter))
+        .with(otel)
+        .try_init()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrubs_escaped_forms() {
+        redact(r#"pa"ss\word"#).unwrap();
+        let line = serde_json::json!({ "message": r#"token pa"ss\word"# }).to_string();
+        assert_eq!(scrub(&line), r#"{"message":"token [REDACTED]"}"#);
+        assert_eq!(scrub(r#"pa"ss\word"#), REDACTED);
+    }
+
+    #[test]
+    fn refuses_short_credentials() {
+        assert!(redact("abc").is_err());
+        assert!(redact("").is_ok());
+    }
+}
@@ -1,22 +1,54 @@
 use axum::{extract::Extension, handler::Handler, middleware, routing::get, Router};
-use chrono::Local;
-use clap::{crate_name, crate_version, App, Arg};
-use env_logger::{Builder, Target};
-use log::LevelFilter;
+use clap::{crate_name, crate_version, App, Arg, SubCommand};
 use std::io::Write;
-use std::net::SocketAddr;
+use std::time::Duration;
+use tower_http::auth::RequireAuthorizationLayer;
 use tower_http::trace::TraceLayer;
 
+mod auth;
+mod check;
 mod error;
+mod export;
 mod handlers;
 mod https;
+mod invoice;
+mod logging;
 mod metrics;
+mod openmetrics;
+mod pushgateway;
+mod remote_write;
+mod server;
+mod shutdown;
+mod sink;
 mod state;
+mod store;
+mod telemetry;
+mod tls;
+mod units;
+mod web_config;
 
+use crate::error::{catch_panic, error_context};
 use crate::metrics::{setup_metrics_recorder, track_metrics};
-use handlers::{handler_404, health, help, metrics, root};
+use auth::Credentials;
+use export::{to_csv, Column};
+use handlers::{
+    daily, export_csv, handler_404, health, help, history, line_items, metrics, ready, root, status,
+};
 use https::create_https_client;
+use invoice::InvoiceSelector;
+use pushgateway::Pushgateway;
+use server::Listener;
+use shutdown::Shutdown;
 use state::State;
+use telemetry::{Otlp, PropagatingMakeSpan};
+use web_config::WebConfig;
+
+fn protect(router: Router, layer: Option<RequireAuthorizationLayer<Credentials>>) -> Router {
+    match layer {
+        Some(layer) => router.route_layer(layer),
+        None => router,
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -33,6 +65,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .default_value("8080")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("listen_address")
+                .short("l")
+                .long("listen_address")
+                .alias("listen-address")
+                .help("Set host:port to listen on, may be repeated, overrides port")
+                .env("ATLAS_BILLING_EXPORTER_LISTEN_ADDRESS")
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("unix_socket")
+                .short("u")
+                .long("unix_socket")
+                .help("Set path of a unix domain socket to listen on")
+                .env("ATLAS_BILLING_EXPORTER_UNIX_SOCKET")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("timeout")
                 .short("t")
@@ -42,6 +94,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .env("ATLAS_BILLING_EXPORTER_TIMEOUT")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("shutdown_timeout")
+                .long("shutdown_timeout")
+                .help("Set seconds in-flight requests get to finish on SIGTERM")
+                .default_value("30")
+                .env("ATLAS_BILLING_EXPORTER_SHUTDOWN_TIMEOUT")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("public_key")
                 .short("k")
@@ -69,61 +129,443 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .env("ATLAS_BILLING_EXPORTER_ORG_ID")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("log_level")
+                .long("log_level")
+                .help("Set log level, or filter directives such as info,hyper=warn")
+                .default_value("info")
+                .env("ATLAS_BILLING_EXPORTER_LOG_LEVEL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log_format")
+                .long("log_format")
+                .help("Set log format")
+                .possible_values(&["json", "text"])
+                .default_value("json")
+                .env("ATLAS_BILLING_EXPORTER_LOG_FORMAT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("otlp_endpoint")
+                .long("otlp_endpoint")
+                .help("Set OpenTelemetry collector endpoint to export traces to")
+                .env("ATLAS_BILLING_EXPORTER_OTLP_ENDPOINT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("otlp_protocol")
+                .long("otlp_protocol")
+                .help("Set protocol used to reach the OpenTelemetry collector")
+                .possible_values(&["grpc", "http"])
+                .default_value("grpc")
+                .env("ATLAS_BILLING_EXPORTER_OTLP_PROTOCOL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("otlp_metrics")
+                .long("otlp_metrics")
+                .help("Push billing metrics to the OpenTelemetry collector every refresh interval")
+                .requires_all(&["otlp_endpoint", "refresh_interval"]),
+        )
+        .arg(
+            Arg::with_name("refresh_interval")
+                .long("refresh_interval")
+                .help("Set seconds between background billing refreshes, served by /metrics instead of fetching on scrape")
+                .env("ATLAS_BILLING_EXPORTER_REFRESH_INTERVAL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("remote_write_url")
+                .long("remote_write_url")
+                .help("Set Prometheus remote_write url to push billing metrics to every refresh interval")
+                .requires("refresh_interval")
+                .env("ATLAS_BILLING_EXPORTER_REMOTE_WRITE_URL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("remote_write_username")
+                .long("remote_write_username")
+                .help("Set username for basic auth against the remote_write url")
+                .env("ATLAS_BILLING_EXPORTER_REMOTE_WRITE_USERNAME")
+                .conflicts_with("remote_write_bearer_token")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("remote_write_password")
+                .long("remote_write_password")
+                .help("Set password for basic auth against the remote_write url")
+                .env("ATLAS_BILLING_EXPORTER_REMOTE_WRITE_PASSWORD")
+                .requires("remote_write_username")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("remote_write_bearer_token")
+                .long("remote_write_bearer_token")
+                .help("Set bearer token sent to the remote_write url")
+                .env("ATLAS_BILLING_EXPORTER_REMOTE_WRITE_BEARER_TOKEN")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("remote_write_retries")
+                .long("remote_write_retries")
+                .help("Set how often a failed remote write is retried, with backoff")
+                .default_value("3")
+                .env("ATLAS_BILLING_EXPORTER_REMOTE_WRITE_RETRIES")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("influx_url")
+                .long("influx_url")
+                .help("Set InfluxDB write url billing metrics are written to on every refresh")
+                .env("ATLAS_BILLING_EXPORTER_INFLUX_URL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("influx_token")
+                .long("influx_token")
+                .help("Set InfluxDB API token")
+                .env("ATLAS_BILLING_EXPORTER_INFLUX_TOKEN")
+                .requires("influx_url")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("statsd_address")
+                .long("statsd_address")
+                .help("Set host:port of a StatsD aggregator billing metrics are sent to on every refresh")
+                .env("ATLAS_BILLING_EXPORTER_STATSD_ADDRESS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("statsd_flavor")
+                .long("statsd_flavor")
+                .help("Set how labels are sent to StatsD, appended to the name or as DogStatsD tags")
+                .possible_values(&["statsd", "dogstatsd"])
+                .default_value("statsd")
+                .env("ATLAS_BILLING_EXPORTER_STATSD_FLAVOR")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics_idle_timeout")
+                .long("metrics_idle_timeout")
+                .help("Set seconds without requests after which http request series are dropped, 0 keeps them")
+                .default_value("300")
+                .env("ATLAS_BILLING_EXPORTER_METRICS_IDLE_TIMEOUT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("staleness")
+                .long("staleness")
+                .help("Set seconds after the last successful billing fetch before /ready fails")
+                .default_value("3600")
+                .env("ATLAS_BILLING_EXPORTER_STALENESS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("web_config")
+                .short("w")
+                .long("web_config")
+                .help("Set path to the yaml file configuring the exporter's http server")
+                .env("ATLAS_BILLING_EXPORTER_WEB_CONFIG")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("database")
+                .short("d")
+                .long("database")
+                .help("Set path to the local database holding invoice history")
+                .env("ATLAS_BILLING_EXPORTER_DATABASE")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("backfill")
+                .about("Store every past invoice of the org in the local database, then exit"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check Atlas reachability, credentials, org access and invoice permissions"),
+        )
+        .subcommand(
+            SubCommand::with_name("once")
+                .about("Collect billing metrics once, print them to stdout, then exit")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("Output format")
+                        .possible_values(&["prometheus", "json"])
+                        .default_value("prometheus")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pushgateway_url")
+                        .long("pushgateway_url")
+                        .help("Push the metrics to this Pushgateway instead of printing them")
+                        .env("ATLAS_BILLING_EXPORTER_PUSHGATEWAY_URL")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("job")
+                        .long("job")
+                        .help("Set job the metrics are pushed under")
+                        .default_value(crate_name!())
+                        .env("ATLAS_BILLING_EXPORTER_PUSHGATEWAY_JOB")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("grouping")
+                        .long("grouping")
+                        .help("Add name=value to the grouping key besides job and org, may be repeated")
+                        .env("ATLAS_BILLING_EXPORTER_PUSHGATEWAY_GROUPING")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .use_delimiter(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("active_orgs")
+                        .long("active_orgs")
+                        .help("Delete groups of the job for orgs not in this comma separated list")
+                        .env("ATLAS_BILLING_EXPORTER_PUSHGATEWAY_ACTIVE_ORGS")
+                        .requires("pushgateway_url")
+                        .use_delimiter(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write invoice line items as csv, then exit")
+                .arg(
+                    Arg::with_name("invoice")
+                        .short("i")
+                        .long("invoice")
+                        .help("Invoice to export: pending, previous or an invoice id")
+                        .default_value("current")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("aggregate")
+                        .short("a")
+                        .long("aggregate")
+                        .help("Sum up line items instead of exporting them one by one")
+                        .possible_values(&["total", "rate", "daily"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("columns")
+                        .short("c")
+                        .long("columns")
+                        .help("Comma separated list of columns, tag:<key> adds a single tag")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("f")
+                        .long("output")
+                        .help("Write to this file instead of stdout")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
-    // Initialize log Builder
-    Builder::new()
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "{{\"date\": \"{}\", \"level\": \"{}\", \"log\": {}}}",
-                Local::now().format("%Y-%m-%dT%H:%M:%S:%f"),
-                record.level(),
-                record.args()
-            )
-        })
-        .target(Target::Stdout)
-        .filter_level(LevelFilter::Info)
-        .parse_default_env()
-        .init();
+    // Initialize logging, before anything gets a chance to log
+    let otlp = Otlp::from_opts(&opts);
+    logging::init(
+        opts.value_of("log_format").unwrap(),
+        opts.value_of("log_level").unwrap(),
+        otlp.as_ref(),
+    )?;
 
     // Set port
     let port: u16 = opts.value_of("port").unwrap().parse().unwrap_or_else(|_| {
-        eprintln!("specified port isn't in a valid range, setting to 8080");
+        tracing::warn!("port not in range, defaulting to 8080");
         8080
     });
 
+    // Set how long in-flight requests and refreshes get to finish on shutdown
+    let shutdown_timeout: u64 = opts
+        .value_of("shutdown_timeout")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|_| {
+            tracing::warn!("shutdown timeout not in range, defaulting to 30");
+            30
+        });
+    let shutdown_timeout = Duration::from_secs(shutdown_timeout);
+
+    // Set how long http request series are kept without new requests
+    let idle_timeout: u64 = opts
+        .value_of("metrics_idle_timeout")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|_| {
+            tracing::warn!("metrics idle timeout not in range, defaulting to 300");
+            300
+        });
+    let idle_timeout = match idle_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+
     // Create state for axum
     let state = State::new(opts.clone()).await?;
 
+    if opts.subcommand_matches("backfill").is_some() {
+        let count = state.backfill().await?;
+        println!("Stored {count} invoices");
+        telemetry::shutdown();
+        return Ok(());
+    }
+
+    if opts.subcommand_matches("check").is_some() {
+        let report = check::run(&state).await;
+        for step in &report.steps {
+            let result = match (step.ok, step.skipped) {
+                (true, _) => "ok",
+                (false, true) => "skipped",
+                (false, false) => "FAILED",
+            };
+            let code = step.error_code.as_deref().unwrap_or_default();
+            println!(
+                "{:<14} {:<7} {} {}",
+                step.name, result, step.diagnosis, code
+            );
+        }
+        telemetry::shutdown();
+        std::process::exit(if report.ok { 0 } else { 1 });
+    }
+
+    if let Some(once) = opts.subcommand_matches("once") {
+        let recorder_handle = setup_metrics_recorder(idle_timeout)?;
+        let snapshot = match state.get_metrics().await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("{e}");
+                telemetry::shutdown();
+                std::process::exit(e.exit_code());
+            }
+        };
+        state.flush_sinks().await;
+        let timeout = state.request_timeout.as_secs();
+        let pushgateway = match Pushgateway::from_opts(once, &state.org, timeout)? {
+            Some(pushgateway) => pushgateway,
+            None => {
+                match once.value_of("format") {
+                    Some("json") => println!("{}", serde_json::to_string_pretty(&snapshot)?),
+                    _ => print!("{}", recorder_handle.render()),
+                }
+                telemetry::shutdown();
+                return Ok(());
+            }
+        };
+
+        // Failed pushes exit with EX_TEMPFAIL, so the next scheduled run tries again
+        if let Err(e) = pushgateway.push(recorder_handle.render()).await {
+            tracing::error!(error = %e, "push to pushgateway failed");
+            telemetry::shutdown();
+            std::process::exit(75);
+        }
+        if let Some(active) = once.values_of("active_orgs") {
+            let mut active: Vec<&str> = active.collect();
+            active.push(&state.org);
+            if let Err(e) = pushgateway.prune(&active).await {
+                tracing::error!(error = %e, "deleting stale pushgateway groups failed");
+                telemetry::shutdown();
+                std::process::exit(75);
+            }
+        }
+        telemetry::shutdown();
+        return Ok(());
+    }
+
+    if let Some(export) = opts.subcommand_matches("export") {
+        let selector: InvoiceSelector = export.value_of("invoice").unwrap_or_default().parse()?;
+        let aggregate = export.value_of("aggregate").map(str::parse).transpose()?;
+        let columns = Column::parse_list(export.value_of("columns"))?;
+        let data = state.get_selected_invoice(&selector).await?;
+        let csv = to_csv(&data, &columns, aggregate)?;
+        match export.value_of("output") {
+            Some(path) => std::fs::write(path, csv)?,
+            None => std::io::stdout().write_all(&csv)?,
+        }
+        telemetry::shutdown();
+        return Ok(());
+    }
+
     // Create prometheus handle
-    let recorder_handle = setup_metrics_recorder();
+    let recorder_handle = setup_metrics_recorder(idle_timeout)?;
+
+    let shutdown = Shutdown::new();
+    shutdown.listen()?;
 
-    // These should be authenticated
-    let base = Router::new().route("/", get(root));
+    match state.refresh_interval {
+        // Keep billing data fresh on a fixed schedule, pushing it when a collector is set
+        Some(interval) => {
+            if let (Some(otlp), true) = (&otlp, opts.is_present("otlp_metrics")) {
+                telemetry::push_metrics(otlp, interval, &state)?;
+            }
+            let refresher = state.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { refresher.refresh_every(interval, shutdown).await });
+        }
+        // Fetch billing data once in the background, so /ready does not wait for the first scrape
+        None => {
+            let warmup = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = warmup.get_metrics().await {
+                    e.body().log("warmup failed");
+                }
+            });
+        }
+    }
+
+    // Each route group can be protected through the web config
+    let web_config = WebConfig::load(opts.value_of("web_config"))?;
+
+    let api = Router::new()
+        .route("/", get(root))
+        .route("/status", get(status))
+        .route("/daily", get(daily))
+        .route("/history", get(history))
+        .route("/api/v1/line-items", get(line_items))
+        .route("/export/csv", get(export_csv));
+    let api = protect(api, Credentials::layer(&web_config.auth.api)?);
+
+    let scrape = Router::new().route("/metrics", get(metrics));
+    let scrape = protect(scrape, Credentials::layer(&web_config.auth.metrics)?);
 
-    // These should NOT be authenticated
     let standard = Router::new()
         .route("/health", get(health))
-        .route("/help", get(help))
-        .route("/metrics", get(metrics));
+        .route("/ready", get(ready))
+        .route("/help", get(help));
+    let standard = protect(standard, Credentials::layer(&web_config.auth.health)?);
 
     let app = Router::new()
-        .merge(base)
+        .merge(api)
+        .merge(scrape)
         .merge(standard)
-        .layer(TraceLayer::new_for_http())
+        // Inside the request span, so latencies can carry its trace id as exemplar
         .route_layer(middleware::from_fn(track_metrics))
-        .layer(Extension(state))
+        .layer(TraceLayer::new_for_http().make_span_with(PropagatingMakeSpan))
+        .layer(middleware::from_fn(catch_panic))
+        .layer(middleware::from_fn(error_context))
+        .layer(Extension(state.clone()))
         .layer(Extension(recorder_handle));
 
     // add a fallback service for handling routes to unknown paths
     let app = app.fallback(handler_404.into_service());
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    println!("Listening on {addr}");
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await?;
+    let listeners = Listener::from_opts(&opts, port)?;
+    let tls = match web_config.tls_server_config {
+        Some(tls_config) => Some(tls::rustls_config(tls_config)?),
+        None => None,
+    };
+
+    server::serve(app, listeners, tls, shutdown.clone(), shutdown_timeout).await?;
+
+    // Refreshes outside of requests, such as the warmup, get what is left of the deadline
+    state.shutdown(shutdown.remaining(shutdown_timeout)).await?;
+    tracing::info!("shutdown complete");
+    telemetry::shutdown();
 
     Ok(())
 }
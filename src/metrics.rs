@@ -1,26 +1,279 @@
 use axum::{http::Request, middleware::Next, response::IntoResponse};
+use chrono::Utc;
 use core::time::Duration;
+use metrics::{Key, KeyName, Label, Recorder, Unit};
+use metrics_exporter_prometheus::formatting::{sanitize_label_key, sanitize_label_value};
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use metrics_util::layers::RouterBuilder;
 use metrics_util::MetricKindMask;
+use opentelemetry::trace::TraceContextExt;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
 use std::time::Instant;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-pub fn setup_metrics_recorder() -> PrometheusHandle {
+use crate::error::Error as RestError;
+use crate::invoice::Sample;
+
+// Billing gauges of the latest snapshot, in a recorder of their own that is replaced on every
+// refresh. Series of line items that disappeared from the invoice go away with the old one.
+static BILLING: RwLock<Option<PrometheusHandle>> = RwLock::new(None);
+
+// Renders every recorder the exporter writes to as one Prometheus text page
+#[derive(Clone)]
+pub struct MetricsHandle {
+    handles: Vec<PrometheusHandle>,
+}
+
+impl MetricsHandle {
+    pub fn render(&self) -> String {
+        let billing = match BILLING.read() {
+            Ok(billing) => billing.as_ref().map(|billing| billing.render()),
+            Err(_) => None,
+        };
+        self.handles
+            .iter()
+            .map(|handle| handle.render())
+            .chain(billing)
+            .collect()
+    }
+}
+
+// Latest observation of a histogram series within a traced request
+#[derive(Debug, Clone)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub value: f64,
+    pub timestamp: f64,
+}
+
+// When each counter and histogram series was first recorded, in seconds since the epoch,
+// exported as _created in OpenMetrics. Keyed by the rendered counter or histogram _count series.
+pub static CREATED: Mutex<Option<HashMap<String, f64>>> = Mutex::new(None);
+pub static EXEMPLARS: Mutex<Option<HashMap<String, Exemplar>>> = Mutex::new(None);
+
+// Series as the Prometheus exporter writes it, name{key="value",...}
+pub fn series(name: &str, labels: &[(&'static str, String)]) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", sanitize_label_key(k), sanitize_label_value(v)))
+        .collect();
+    match labels.is_empty() {
+        true => name.to_string(),
+        false => format!("{name}{{{}}}", labels.join(",")),
+    }
+}
+
+fn now() -> f64 {
+    Utc::now().timestamp_millis() as f64 / 1000.0
+}
+
+fn created(series: String) {
+    if let Ok(mut created) = CREATED.lock() {
+        created
+            .get_or_insert_with(HashMap::new)
+            .entry(series)
+            .or_insert_with(now);
+    }
+}
+
+pub fn increment_counter(name: &'static str, labels: &[(&'static str, String)]) {
+    metrics::increment_counter!(name, labels);
+    created(series(name, labels));
+}
+
+// Record into a histogram, keeping the trace id of the current span as exemplar when it is traced
+pub fn record_histogram(name: &'static str, value: f64, labels: &[(&'static str, String)]) {
+    metrics::histogram!(name, value, labels);
+    let series = series(&format!("{name}_count"), labels);
+    created(series.clone());
+
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    if !span_context.is_valid() {
+        return;
+    }
+    let exemplar = Exemplar {
+        trace_id: span_context.trace_id().to_string(),
+        value,
+        timestamp: now(),
+    };
+    if let Ok(mut exemplars) = EXEMPLARS.lock() {
+        exemplars
+            .get_or_insert_with(HashMap::new)
+            .insert(series, exemplar);
+    }
+}
+
+// Forget series the recorder no longer renders, they start over when recorded again
+pub fn forget_expired(rendered: &str) {
+    let rendered: HashSet<&str> = rendered
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.rsplit_once(' '))
+        .map(|(series, _)| series)
+        .collect();
+
+    if let Ok(mut created) = CREATED.lock() {
+        if let Some(created) = created.as_mut() {
+            created.retain(|series, _| rendered.contains(series.as_str()));
+        }
+    }
+    if let Ok(mut exemplars) = EXEMPLARS.lock() {
+        if let Some(exemplars) = exemplars.as_mut() {
+            exemplars.retain(|series, _| rendered.contains(series.as_str()));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+// Help text and unit of a metric the exporter serves
+#[derive(Debug)]
+pub struct Description {
+    pub name: &'static str,
+    pub kind: Kind,
+    // Rendered as # UNIT in OpenMetrics, which only allows it when the family name ends in the
+    // unit. Passed to the recorder as well when the metrics crate knows it.
+    pub unit: Option<&'static str>,
+    pub help: &'static str,
+}
+
+pub const DESCRIPTIONS: [Description; 8] = [
+    Description {
+        name: "atlas_billing_item_cents_total",
+        kind: Kind::Gauge,
+        unit: None,
+        help: "Atlas billing total cost per sku, in cents",
+    },
+    Description {
+        name: "atlas_billing_item_cents_rate",
+        kind: Kind::Gauge,
+        unit: None,
+        help: "Atlas billing rate per sku, in cents per hour, for the most recent day",
+    },
+    Description {
+        name: "atlas_billing_item_unit_price_cents",
+        kind: Kind::Gauge,
+        unit: Some("cents"),
+        help: "Atlas average unit price per sku, in cents per normalized unit",
+    },
+    Description {
+        name: "atlas_billing_item_cents_daily",
+        kind: Kind::Gauge,
+        unit: None,
+        help: "Atlas billing cost per sku for each day of the invoice, in cents",
+    },
+    Description {
+        name: "atlas_billing_unknown_units_total",
+        kind: Kind::Counter,
+        unit: None,
+        help: "Unit prices skipped because Atlas reported a unit the exporter does not know",
+    },
+    Description {
+        name: "atlas_billing_upstream_errors_total",
+        kind: Kind::Counter,
+        unit: None,
+        help: "Failed Atlas responses per Atlas errorCode, or per http status when Atlas sent none",
+    },
+    Description {
+        name: "http_requests_total",
+        kind: Kind::Counter,
+        unit: None,
+        help: "Requests served by the exporter, per method, path and status",
+    },
+    Description {
+        name: "atlas_billing_http_requests_duration_seconds",
+        kind: Kind::Histogram,
+        unit: Some("seconds"),
+        help: "Time taken to serve requests, per method, path and status",
+    },
+];
+
+// Unit of a metric, as listed in DESCRIPTIONS
+pub fn unit(name: &str) -> Option<&'static str> {
+    DESCRIPTIONS
+        .iter()
+        .find(|description| description.name == name)
+        .and_then(|description| description.unit)
+}
+
+// Register the help texts and units of every metric of a kind, rendered as # HELP and, for
+// OpenMetrics, # UNIT
+fn describe(recorder: &dyn Recorder, kinds: &[Kind]) {
+    for description in DESCRIPTIONS.iter().filter(|d| kinds.contains(&d.kind)) {
+        let key = KeyName::from(description.name);
+        let unit = description.unit.and_then(Unit::from_string);
+        match description.kind {
+            Kind::Counter => recorder.describe_counter(key, unit, description.help),
+            Kind::Gauge => recorder.describe_gauge(key, unit, description.help),
+            Kind::Histogram => recorder.describe_histogram(key, unit, description.help),
+        }
+    }
+}
+
+// Replace the billing gauges with the samples of a new snapshot
+pub fn set_billing(samples: &[Sample]) {
+    let recorder = PrometheusBuilder::new().build_recorder();
+    describe(&recorder, &[Kind::Gauge]);
+
+    for sample in samples {
+        let labels: Vec<Label> = sample
+            .labels
+            .iter()
+            .map(|(key, value)| Label::new(*key, value.clone()))
+            .collect();
+        recorder
+            .register_gauge(&Key::from_parts(sample.name, labels))
+            .set(sample.value);
+    }
+
+    if let Ok(mut billing) = BILLING.write() {
+        *billing = Some(recorder.handle());
+    }
+}
+
+// Install the global recorder. HTTP self-metrics are dropped after idle_timeout without
+// requests, everything else stays until the exporter exits.
+pub fn setup_metrics_recorder(idle_timeout: Option<Duration>) -> Result<MetricsHandle, RestError> {
     const EXPONENTIAL_SECONDS: &[f64] = &[
         0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
     ];
 
-    PrometheusBuilder::new()
-        .idle_timeout(
-            MetricKindMask::COUNTER | MetricKindMask::GAUGE,
-            Some(Duration::from_secs(10)),
-        )
+    let main = PrometheusBuilder::new().build_recorder();
+    let requests = PrometheusBuilder::new()
+        .idle_timeout(MetricKindMask::ALL, idle_timeout)
+        .build_recorder();
+    let durations = PrometheusBuilder::new()
+        .idle_timeout(MetricKindMask::ALL, idle_timeout)
         .set_buckets_for_metric(
             Matcher::Full("atlas_billing_http_requests_duration_seconds".to_string()),
             EXPONENTIAL_SECONDS,
-        )
-        .unwrap()
-        .install_recorder()
-        .unwrap()
+        )?
+        .build_recorder();
+    let handle = MetricsHandle {
+        handles: vec![main.handle(), requests.handle(), durations.handle()],
+    };
+
+    let mut router = RouterBuilder::from_recorder(main);
+    router
+        .add_route(MetricKindMask::COUNTER, "http_requests_total", requests)
+        .add_route(
+            MetricKindMask::HISTOGRAM,
+            "atlas_billing_http_requests_duration_seconds",
+            durations,
+        );
+    metrics::set_boxed_recorder(Box::new(router.build()))
+        .map_err(|e| RestError::Recorder(e.into()))?;
+    describe(metrics::recorder(), &[Kind::Counter, Kind::Histogram]);
+
+    Ok(handle)
 }
 
 pub async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
@@ -37,11 +290,11 @@ pub async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> impl IntoRespon
         ("status", status),
     ];
 
-    metrics::increment_counter!("http_requests_total", &labels);
-    metrics::histogram!(
+    increment_counter("http_requests_total", &labels);
+    record_histogram(
         "atlas_billing_http_requests_duration_seconds",
         latency,
-        &labels
+        &labels,
     );
 
     response
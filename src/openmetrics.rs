@@ -0,0 +1,269 @@
+use axum::http::HeaderValue;
+use std::collections::HashMap;
+
+use crate::metrics::{self, Exemplar, CREATED, EXEMPLARS};
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// Whether the Accept header asks for OpenMetrics, as Prometheus does by default
+pub fn accepts(accept: Option<&HeaderValue>) -> bool {
+    let accept = match accept.and_then(|accept| accept.to_str().ok()) {
+        Some(accept) => accept,
+        None => return false,
+    };
+
+    accept.split(',').any(|range| {
+        let mut params = range.split(';').map(str::trim);
+        let refused =
+            |param: &str| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0);
+        params.next() == Some("application/openmetrics-text") && !params.any(refused)
+    })
+}
+
+// Split a series into its name and the labels between the braces
+fn split_series(series: &str) -> (&str, &str) {
+    match series.split_once('{') {
+        Some((name, labels)) => (name, labels.strip_suffix('}').unwrap_or(labels)),
+        None => (series, ""),
+    }
+}
+
+fn with_labels(name: &str, labels: &str) -> String {
+    match labels.is_empty() {
+        true => name.to_string(),
+        false => format!("{name}{{{labels}}}"),
+    }
+}
+
+// Labels of a histogram bucket without le, and the le bound
+fn split_bucket(labels: &str) -> Option<(&str, f64)> {
+    let (labels, le) = match labels.rsplit_once(",le=") {
+        Some(split) => split,
+        None => ("", labels.strip_prefix("le=")?),
+    };
+    let le = le.trim_matches('"').parse().ok()?;
+    Some((labels, le))
+}
+
+// Convert the Prometheus text the recorder renders into OpenMetrics. Counter families lose their
+// _total suffix, counters and histograms get _created, and the bucket a traced request fell into
+// carries its trace id as exemplar.
+pub fn render(prometheus: &str) -> String {
+    let created: HashMap<String, f64> = CREATED
+        .lock()
+        .ok()
+        .and_then(|created| created.clone())
+        .unwrap_or_default();
+    let exemplars: HashMap<String, Exemplar> = EXEMPLARS
+        .lock()
+        .ok()
+        .and_then(|exemplars| exemplars.clone())
+        .unwrap_or_default();
+
+    let mut output = String::new();
+    let mut help = None;
+    let mut family = String::new();
+    let mut kind = "";
+    // Series and bound of the previous bucket, the exemplar goes on the first bucket it fits in
+    let mut previous_bucket: Option<(String, f64)> = None;
+
+    for line in prometheus.lines() {
+        // OpenMetrics allows no blank lines
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            help = rest.split_once(' ');
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("# TYPE ") {
+            let (name, metric_type) = match rest.split_once(' ') {
+                Some(split) => split,
+                None => continue,
+            };
+            kind = metric_type;
+            family = match kind {
+                "counter" => name.strip_suffix("_total").unwrap_or(name).to_string(),
+                _ => name.to_string(),
+            };
+
+            output.push_str(&format!("# TYPE {family} {kind}\n"));
+            if let Some(unit) = metrics::unit(name) {
+                output.push_str(&format!("# UNIT {family} {unit}\n"));
+            }
+            if let Some((_, text)) = help.take().filter(|(help_name, _)| *help_name == name) {
+                output.push_str(&format!("# HELP {family} {text}\n"));
+            }
+            continue;
+        }
+
+        let series = match line.rsplit_once(' ') {
+            Some((series, _)) => series,
+            None => continue,
+        };
+        let (name, labels) = split_series(series);
+        output.push_str(line);
+
+        if kind == "histogram" && name == format!("{family}_bucket") {
+            if let Some((labels, le)) = split_bucket(labels) {
+                let count = with_labels(&format!("{family}_count"), labels);
+                if let Some(exemplar) = exemplars.get(&count) {
+                    let below = previous_bucket
+                        .as_ref()
+                        .filter(|(previous, _)| *previous == count)
+                        .map_or(f64::NEG_INFINITY, |(_, previous_le)| *previous_le);
+                    if exemplar.value <= le && exemplar.value > below {
+                        output.push_str(&format!(
+                            " # {{trace_id=\"{}\"}} {} {}",
+                            exemplar.trace_id, exemplar.value, exemplar.timestamp
+                        ));
+                    }
+                }
+                previous_bucket = Some((count, le));
+            }
+        }
+        output.push('\n');
+
+        // _created follows the last sample of each counter and histogram series
+        let last = match kind {
+            "counter" => true,
+            "histogram" => name == format!("{family}_count"),
+            _ => false,
+        };
+        if let Some(created) = created.get(series).filter(|_| last) {
+            let series = with_labels(&format!("{family}_created"), labels);
+            output.push_str(&format!("{series} {created}\n"));
+        }
+    }
+
+    output.push_str("# EOF\n");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_created(series: &str, created: f64) {
+        CREATED
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert(series.to_string(), created);
+    }
+
+    #[test]
+    fn accepts_openmetrics_unless_refused() {
+        let accept = |value: &'static str| accepts(Some(&HeaderValue::from_static(value)));
+        assert!(accept(
+            "application/openmetrics-text; version=1.0.0; charset=utf-8,text/plain;q=0.5"
+        ));
+        assert!(accept("text/plain, application/openmetrics-text"));
+        assert!(!accept("application/openmetrics-text; q=0"));
+        assert!(!accept("text/plain; version=0.0.4"));
+        assert!(!accepts(None));
+    }
+
+    #[test]
+    fn splits_bucket_bounds() {
+        assert_eq!(
+            split_bucket(r#"path="/a",le="0.01""#),
+            Some((r#"path="/a""#, 0.01))
+        );
+        assert_eq!(split_bucket(r#"le="+Inf""#), Some(("", f64::INFINITY)));
+        assert_eq!(split_bucket(r#"path="/a""#), None);
+    }
+
+    #[test]
+    fn renders_counters_without_total_and_with_created() {
+        set_created(r#"http_requests_total{path="/counter"}"#, 1700000000.5);
+        let prometheus = r#"# HELP http_requests_total Requests served
+# TYPE http_requests_total counter
+http_requests_total{path="/counter"} 3
+
+"#;
+        assert_eq!(
+            render(prometheus),
+            r#"# TYPE http_requests counter
+# HELP http_requests Requests served
+http_requests_total{path="/counter"} 3
+http_requests_created{path="/counter"} 1700000000.5
+# EOF
+"#
+        );
+    }
+
+    #[test]
+    fn renders_gauges_with_their_unit() {
+        let prometheus = r#"# HELP atlas_billing_item_unit_price_cents Unit price
+# TYPE atlas_billing_item_unit_price_cents gauge
+atlas_billing_item_unit_price_cents{sku="gauge"} 1.5
+# TYPE atlas_billing_item_cents_rate gauge
+atlas_billing_item_cents_rate{sku="gauge"} 0.25
+"#;
+        assert_eq!(
+            render(prometheus),
+            r#"# TYPE atlas_billing_item_unit_price_cents gauge
+# UNIT atlas_billing_item_unit_price_cents cents
+# HELP atlas_billing_item_unit_price_cents Unit price
+atlas_billing_item_unit_price_cents{sku="gauge"} 1.5
+# TYPE atlas_billing_item_cents_rate gauge
+atlas_billing_item_cents_rate{sku="gauge"} 0.25
+# EOF
+"#
+        );
+    }
+
+    #[test]
+    fn renders_histograms_with_exemplar_on_the_first_fitting_bucket() {
+        let name = "atlas_billing_http_requests_duration_seconds";
+        set_created(&format!(r#"{name}_count{{path="/traced"}}"#), 1700000001.0);
+        EXEMPLARS
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert(
+                format!(r#"{name}_count{{path="/traced"}}"#),
+                Exemplar {
+                    trace_id: "0af7651916cd43dd8448eb211c80319c".to_string(),
+                    value: 0.007,
+                    timestamp: 1700000002.5,
+                },
+            );
+
+        let prometheus = format!(
+            r#"# TYPE {name} histogram
+{name}_bucket{{path="/traced",le="0.005"}} 0
+{name}_bucket{{path="/traced",le="0.01"}} 1
+{name}_bucket{{path="/traced",le="+Inf"}} 1
+{name}_sum{{path="/traced"}} 0.007
+{name}_count{{path="/traced"}} 1
+{name}_bucket{{path="/untraced",le="0.005"}} 1
+{name}_bucket{{path="/untraced",le="0.01"}} 1
+{name}_bucket{{path="/untraced",le="+Inf"}} 1
+{name}_sum{{path="/untraced"}} 0.001
+{name}_count{{path="/untraced"}} 1
+"#
+        );
+        assert_eq!(
+            render(&prometheus),
+            format!(
+                r#"# TYPE {name} histogram
+# UNIT {name} seconds
+{name}_bucket{{path="/traced",le="0.005"}} 0
+{name}_bucket{{path="/traced",le="0.01"}} 1 # {{trace_id="0af7651916cd43dd8448eb211c80319c"}} 0.007 1700000002.5
+{name}_bucket{{path="/traced",le="+Inf"}} 1
+{name}_sum{{path="/traced"}} 0.007
+{name}_count{{path="/traced"}} 1
+{name}_created{{path="/traced"}} 1700000001
+{name}_bucket{{path="/untraced",le="0.005"}} 1
+{name}_bucket{{path="/untraced",le="0.01"}} 1
+{name}_bucket{{path="/untraced",le="+Inf"}} 1
+{name}_sum{{path="/untraced"}} 0.001
+{name}_count{{path="/untraced"}} 1
+# EOF
+"#
+            )
+        );
+    }
+}
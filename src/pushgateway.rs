@@ -0,0 +1,168 @@
+use clap::{crate_name, ArgMatches};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::Duration;
+use url::Url;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Group as listed by the Pushgateway, only the labels of its grouping key are needed
+#[derive(Deserialize, Debug)]
+struct Group {
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Groups {
+    data: Vec<Group>,
+}
+
+// Pushes the metrics of a one-shot run to a Pushgateway, set with once --pushgateway_url
+#[derive(Debug, Clone)]
+pub struct Pushgateway {
+    client: reqwest::Client,
+    url: Url,
+    job: String,
+    // Grouping key besides the job, always starting with the org
+    grouping: Vec<(String, String)>,
+}
+
+impl Pushgateway {
+    pub fn from_opts(opts: &ArgMatches, org: &str, timeout: u64) -> BoxResult<Option<Self>> {
+        let url = match opts.value_of("pushgateway_url") {
+            Some(url) => Url::parse(url)?,
+            None => return Ok(None),
+        };
+        let job = opts.value_of("job").unwrap_or(crate_name!()).to_string();
+
+        let mut grouping = vec![("org".to_string(), org.to_string())];
+        for pair in opts.values_of("grouping").into_iter().flatten() {
+            match pair.split_once('=') {
+                Some((name, value)) if !name.is_empty() && name != "org" && name != "job" => {
+                    grouping.push((name.to_string(), value.to_string()))
+                }
+                _ => {
+                    return Err(
+                        format!("invalid grouping label {pair}, expected name=value").into(),
+                    )
+                }
+            }
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()?;
+
+        Ok(Some(Pushgateway {
+            client,
+            url,
+            job,
+            grouping,
+        }))
+    }
+
+    // Url of a group, /metrics/job/<job>/<name>/<value>... Values the path cannot carry as they
+    // are, such as ones containing a slash, are base64 encoded. An empty value is written as =,
+    // which the Pushgateway reads as empty base64.
+    fn group_url<'a>(&self, grouping: impl Iterator<Item = (&'a str, &'a str)>) -> BoxResult<Url> {
+        let mut url = self.url.clone();
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| "pushgateway url cannot be a base")?;
+            segments
+                .pop_if_empty()
+                .extend(["metrics", "job", &self.job]);
+            for (name, value) in grouping {
+                match value {
+                    "" => segments.extend([format!("{name}@base64"), "=".to_string()]),
+                    value if value.contains('/') => segments.extend([
+                        format!("{name}@base64"),
+                        base64::encode_config(value, base64::URL_SAFE),
+                    ]),
+                    value => segments.extend([name, value]),
+                };
+            }
+        }
+        Ok(url)
+    }
+
+    // Replace every metric of this run's group with the rendered ones
+    pub async fn push(&self, metrics: String) -> BoxResult<()> {
+        let grouping = self.grouping.iter().map(|(n, v)| (n.as_str(), v.as_str()));
+        let url = self.group_url(grouping)?;
+        self.client
+            .put(url)
+            .header(reqwest::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(metrics)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    // Delete the groups of this job whose org is not among the active ones, returning how many
+    pub async fn prune(&self, active: &[&str]) -> BoxResult<usize> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| "pushgateway url cannot be a base")?
+            .pop_if_empty()
+            .extend(["api", "v1", "metrics"]);
+        let groups: Groups = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let mut deleted = 0;
+        for group in groups.data {
+            if group.labels.get("job") != Some(&self.job) {
+                continue;
+            }
+            let org = match group.labels.get("org") {
+                Some(org) if !active.contains(&org.as_str()) => org,
+                _ => continue,
+            };
+
+            let grouping = group
+                .labels
+                .iter()
+                .filter(|(name, _)| *name != "job")
+                .map(|(n, v)| (n.as_str(), v.as_str()));
+            let url = self.group_url(grouping)?;
+            self.client.delete(url).send().await?.error_for_status()?;
+            tracing::info!(job = %self.job, org = %org, "deleted stale pushgateway group");
+            deleted += 1;
+        }
+
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pushgateway() -> Pushgateway {
+        Pushgateway {
+            client: reqwest::Client::new(),
+            url: Url::parse("http://localhost:9091/").unwrap(),
+            job: "exporter".to_string(),
+            grouping: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn encodes_grouping_values() {
+        let grouping = [("org", "5f1a"), ("env", ""), ("path", "a/b")];
+        let url = pushgateway().group_url(grouping.into_iter()).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http://localhost:9091/metrics/job/exporter/org/5f1a/env@base64/=/path@base64/YS9i"
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use chrono::Utc;
+use clap::{crate_name, crate_version, ArgMatches};
+use prost::Message;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER, USER_AGENT};
+use reqwest::StatusCode;
+use std::error::Error;
+use std::time::Duration;
+
+use crate::invoice::Snapshot;
+use crate::logging;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Wait before the first retry, doubled for every retry after it
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// Messages of the remote_write 1.0 protocol, see prometheus/prompb/remote.proto
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    // Milliseconds since the epoch
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+// Only throttling and receiver failures are retried. Other rejections are final, resending the
+// same data would not change the answer.
+fn retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+#[derive(Debug, Clone)]
+enum Auth {
+    Basic(String, Option<String>),
+    Bearer(String),
+}
+
+// Pushes the billing series to a Prometheus remote_write receiver, set with --remote_write_url
+#[derive(Debug, Clone)]
+pub struct RemoteWrite {
+    client: reqwest::Client,
+    url: String,
+    auth: Option<Auth>,
+    retries: u32,
+}
+
+impl RemoteWrite {
+    pub fn from_opts(opts: &ArgMatches, timeout: u64) -> BoxResult<Option<Self>> {
+        let url = match opts.value_of("remote_write_url") {
+            Some(url) => url.to_string(),
+            None => return Ok(None),
+        };
+
+        let auth = match (
+            opts.value_of("remote_write_username"),
+            opts.value_of("remote_write_bearer_token"),
+        ) {
+            (Some(username), _) => {
+                let password = opts.value_of("remote_write_password").map(str::to_string);
+                Some(Auth::Basic(username.to_string(), password))
+            }
+            (None, Some(token)) => Some(Auth::Bearer(token.to_string())),
+            (None, None) => None,
+        };
+        match &auth {
+            Some(Auth::Basic(_, Some(password))) => logging::redact(password)?,
+            Some(Auth::Bearer(token)) => logging::redact(token)?,
+            _ => {}
+        }
+
+        let retries = opts
+            .value_of("remote_write_retries")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|_| {
+                tracing::warn!("remote write retries not in range, defaulting to 3");
+                3
+            });
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()?;
+
+        Ok(Some(RemoteWrite {
+            client,
+            url,
+            auth,
+            retries,
+        }))
+    }
+
+    // One series per sample, all stamped with the time of the push
+    fn encode(snapshot: &Snapshot) -> Vec<u8> {
+        let timestamp = Utc::now().timestamp_millis();
+        let timeseries = snapshot
+            .samples()
+            .into_iter()
+            .map(|sample| {
+                let mut labels: Vec<Label> = sample
+                    .labels
+                    .into_iter()
+                    .map(|(name, value)| Label {
+                        name: name.to_string(),
+                        value,
+                    })
+                    .collect();
+                labels.push(Label {
+                    name: "__name__".to_string(),
+                    value: sample.name.to_string(),
+                });
+                // Receivers expect the labels sorted by name
+                labels.sort_by(|a, b| a.name.cmp(&b.name));
+
+                TimeSeries {
+                    labels,
+                    samples: vec![Sample {
+                        value: sample.value,
+                        timestamp,
+                    }],
+                }
+            })
+            .collect();
+
+        WriteRequest { timeseries }.encode_to_vec()
+    }
+
+    async fn send(&self, body: Vec<u8>) -> reqwest::Result<reqwest::Response> {
+        let request = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .header(CONTENT_ENCODING, "snappy")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .header(USER_AGENT, concat!(crate_name!(), "/", crate_version!()))
+            .body(body);
+        let request = match &self.auth {
+            Some(Auth::Basic(username, password)) => {
+                request.basic_auth(username, password.as_ref())
+            }
+            Some(Auth::Bearer(token)) => request.bearer_auth(token),
+            None => request,
+        };
+        request.send().await
+    }
+
+    // Push the snapshot, retrying with backoff on connection errors and retryable statuses
+    #[tracing::instrument(name = "remote_write", skip_all, fields(invoice = %snapshot.invoice_id))]
+    pub async fn push(&self, snapshot: &Snapshot) -> BoxResult<()> {
+        let body = snap::raw::Encoder::new().compress_vec(&Self::encode(snapshot))?;
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            let error: Box<dyn Error + Send + Sync> = match self.send(body.clone()).await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => {
+                    let status = response.status();
+                    let retry_after = response
+                        .headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse().ok())
+                        .map(Duration::from_secs);
+                    let text = response.text().await.unwrap_or_default();
+                    let error = format!("receiver answered {status}: {}", text.trim()).into();
+
+                    if !retryable(status) {
+                        return Err(error);
+                    }
+                    if let Some(retry_after) = retry_after {
+                        backoff = retry_after.min(MAX_BACKOFF);
+                    }
+                    error
+                }
+                Err(e) => e.into(),
+            };
+
+            if attempt > self.retries {
+                return Err(error);
+            }
+            tracing::warn!(attempt, error = %error, "remote write failed, retrying");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::invoice::Compressed;
+
+    fn record(day: &str) -> Compressed {
+        serde_json::from_value(serde_json::json!({
+            "clusterName": "c0",
+            "quantity": 24.0,
+            "groupName": "group",
+            "sku": "INSTANCE",
+            "totalPriceCents": 48,
+            "unit": "HOURS",
+            "unitPriceDollars": 0.02,
+            "tags": {"project": ["web"]},
+            "startDate": format!("{day}T00:00:00Z"),
+            "endDate": format!("{day}T23:59:59Z"),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn encodes_named_series_with_sorted_labels() {
+        let snapshot = Snapshot {
+            invoice_id: "65e1a2b3c4d5e6f708091a2b".to_string(),
+            totals: vec![record("2024-03-01")],
+            rates: vec![record("2024-03-01")],
+            daily: vec![record("2024-03-01")],
+        };
+        let request = WriteRequest::decode(RemoteWrite::encode(&snapshot).as_slice()).unwrap();
+
+        let names: Vec<&str> = request
+            .timeseries
+            .iter()
+            .filter_map(|series| series.labels.iter().find(|l| l.name == "__name__"))
+            .map(|label| label.value.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "atlas_billing_item_cents_total",
+                "atlas_billing_item_cents_rate",
+                "atlas_billing_item_unit_price_cents",
+                "atlas_billing_item_cents_daily",
+            ]
+        );
+
+        for series in &request.timeseries {
+            let labels: Vec<&str> = series.labels.iter().map(|l| l.name.as_str()).collect();
+            let mut sorted = labels.clone();
+            sorted.sort();
+            assert_eq!(labels, sorted);
+            assert_eq!(series.samples.len(), 1);
+        }
+        let daily = &request.timeseries[3];
+        assert_eq!(daily.samples[0].value, 48.0);
+        assert!(daily
+            .labels
+            .iter()
+            .any(|l| l.name == "date" && l.value == "2024-03-01"));
+    }
+
+    #[test]
+    fn retries_throttling_and_server_errors_only() {
+        for status in [429, 500, 502, 503, 504] {
+            assert!(retryable(StatusCode::from_u16(status).unwrap()), "{status}");
+        }
+        for status in [400, 401, 403, 404, 409, 413] {
+            assert!(
+                !retryable(StatusCode::from_u16(status).unwrap()),
+                "{status}"
+            );
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use clap::ArgMatches;
+use futures::future::{BoxFuture, FutureExt};
+use std::error::Error;
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::UnixListener;
+
+use crate::shutdown::Shutdown;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Address the http server accepts connections on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listener {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listener::Tcp(addr) => write!(f, "{addr}"),
+            Listener::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Listener {
+    // Collect listeners from --listen_address and --unix_socket, falling back to all interfaces on --port
+    pub fn from_opts(opts: &ArgMatches, port: u16) -> BoxResult<Vec<Listener>> {
+        let mut listeners = Vec::new();
+
+        for address in opts.values_of("listen_address").into_iter().flatten() {
+            let addrs = address
+                .to_socket_addrs()
+                .map_err(|e| format!("Could not resolve listen address {address}: {e}"))?;
+            for addr in addrs {
+                let listener = Listener::Tcp(addr);
+                if !listeners.contains(&listener) {
+                    listeners.push(listener);
+                }
+            }
+        }
+
+        if let Some(path) = opts.value_of("unix_socket") {
+            listeners.push(Listener::Unix(PathBuf::from(path)));
+        }
+
+        if listeners.is_empty() {
+            listeners.push(Listener::Tcp(SocketAddr::from(([0, 0, 0, 0], port))));
+        }
+
+        Ok(listeners)
+    }
+}
+
+fn serve_tcp(
+    app: Router,
+    addr: SocketAddr,
+    tls: Option<RustlsConfig>,
+    shutdown: Shutdown,
+) -> BoxFuture<'static, BoxResult<()>> {
+    async move {
+        match tls {
+            Some(rustls_config) => {
+                let handle = Handle::new();
+                let graceful = handle.clone();
+                tokio::spawn(async move {
+                    shutdown.wait().await;
+                    graceful.graceful_shutdown(None);
+                });
+
+                tracing::info!(address = %addr, tls = true, "listening");
+                axum_server::bind_rustls(addr, rustls_config)
+                    .handle(handle)
+                    .serve(app.into_make_service())
+                    .await?;
+            }
+            None => {
+                tracing::info!(address = %addr, "listening");
+                axum::Server::try_bind(&addr)?
+                    .serve(app.into_make_service())
+                    .with_graceful_shutdown(async move { shutdown.wait().await })
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+// Plain http only, the socket is meant for sidecars sharing the pod
+fn serve_unix(app: Router, path: PathBuf, shutdown: Shutdown) -> BoxFuture<'static, BoxResult<()>> {
+    async move {
+        // Remove a socket left behind by a previous run, but never any other kind of file
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&path)?,
+            Ok(_) => {
+                return Err(format!(
+                    "{} exists and is not a socket, not replacing it",
+                    path.display()
+                )
+                .into())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Could not inspect {}: {e}", path.display()).into()),
+        }
+
+        let listener = UnixListener::bind(&path)
+            .map_err(|e| format!("Could not bind {}: {e}", path.display()))?;
+        let accept = hyper::server::accept::poll_fn(move |cx| {
+            listener
+                .poll_accept(cx)
+                .map(|result| Some(result.map(|(stream, _)| stream)))
+        });
+
+        tracing::info!(address = %format!("unix:{}", path.display()), "listening");
+        axum::Server::builder(accept)
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(async move { shutdown.wait().await })
+            .await?;
+
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+    .boxed()
+}
+
+// Serve the app on every listener, returning as soon as one of them fails. After shutdown is
+// triggered new connections are refused, and in-flight requests get until the deadline to finish.
+pub async fn serve(
+    app: Router,
+    listeners: Vec<Listener>,
+    tls: Option<RustlsConfig>,
+    shutdown: Shutdown,
+    deadline: Duration,
+) -> BoxResult<()> {
+    let servers = listeners.into_iter().map(|listener| match listener {
+        Listener::Tcp(addr) => serve_tcp(app.clone(), addr, tls.clone(), shutdown.clone()),
+        Listener::Unix(path) => serve_unix(app.clone(), path, shutdown.clone()),
+    });
+    let serving = futures::future::try_join_all(servers);
+    tokio::pin!(serving);
+
+    tokio::select! {
+        result = &mut serving => {
+            result?;
+        }
+        _ = shutdown.wait() => {
+            match tokio::time::timeout(deadline, serving).await {
+                Ok(result) => {
+                    result?;
+                }
+                Err(_) => tracing::warn!("shutdown deadline passed, dropping open connections"),
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+// Broadcasts the shutdown signal to every server and background task
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    tx: watch::Sender<Option<Instant>>,
+    rx: watch::Receiver<Option<Instant>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, rx) = watch::channel(None);
+        Shutdown { tx, rx }
+    }
+
+    pub fn trigger(&self) {
+        self.tx.send_if_modified(|triggered| match triggered {
+            Some(_) => false,
+            None => {
+                *triggered = Some(Instant::now());
+                true
+            }
+        });
+    }
+
+    // Time left until the deadline, counted from when shutdown was triggered
+    pub fn remaining(&self, deadline: Duration) -> Duration {
+        match *self.rx.borrow() {
+            Some(triggered) => deadline.saturating_sub(triggered.elapsed()),
+            None => deadline,
+        }
+    }
+
+    // Resolves once shutdown has been triggered
+    pub async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        // The sender lives as long as self, so this only fails once nobody can trigger anymore
+        let _ = rx.wait_for(|triggered| triggered.is_some()).await;
+    }
+
+    // Trigger shutdown on SIGINT or SIGTERM
+    pub fn listen(&self) -> std::io::Result<()> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let shutdown = self.clone();
+
+        tokio::spawn(async move {
+            let name = tokio::select! {
+                _ = sigterm.recv() => "SIGTERM",
+                _ = sigint.recv() => "SIGINT",
+            };
+            tracing::info!(signal = name, "shutting down");
+            shutdown.trigger();
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,340 @@
+use chrono::Utc;
+use clap::ArgMatches;
+use futures::future::BoxFuture;
+use std::error::Error;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+use crate::invoice::Sample;
+use crate::logging;
+use crate::metrics;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Keeps StatsD datagrams below the usual 1500 byte MTU
+const MAX_DATAGRAM: usize = 1432;
+// Longest a single write to a sink may take, so writes to a sink that is down do not pile up
+const EMIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Output every billing refresh writes its samples to
+pub trait Sink: fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+    fn emit<'a>(&'a self, samples: &'a [Sample]) -> BoxFuture<'a, BoxResult<()>>;
+
+    // Whether the refresh waits for this sink. Only the recorder behind /metrics has to be
+    // current before a scrape is answered, the others are written in the background.
+    fn awaited(&self) -> bool {
+        false
+    }
+}
+
+// Write the samples to every sink, returning the writes left running in the background. A
+// failing sink is logged, it does not fail the refresh or hold up the other sinks.
+pub async fn emit_all(sinks: &[Arc<dyn Sink>], samples: Vec<Sample>) -> Vec<JoinHandle<()>> {
+    let samples = Arc::new(samples);
+    let mut background = Vec::new();
+
+    for sink in sinks {
+        let awaited = sink.awaited();
+        let sink = sink.clone();
+        let samples = samples.clone();
+        let emit = async move {
+            let result = match tokio::time::timeout(EMIT_TIMEOUT, sink.emit(&samples)).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("no answer within {}s", EMIT_TIMEOUT.as_secs()).into()),
+            };
+            if let Err(e) = result {
+                tracing::error!(sink = sink.name(), error = %e, "emitting billing metrics failed");
+            }
+        }
+        .in_current_span();
+
+        match awaited {
+            true => emit.await,
+            false => background.push(tokio::spawn(emit)),
+        }
+    }
+
+    background
+}
+
+// Build the configured sinks, the Prometheus recorder behind /metrics always comes first
+pub fn from_opts(opts: &ArgMatches, timeout: u64) -> BoxResult<Vec<Arc<dyn Sink>>> {
+    let mut sinks: Vec<Arc<dyn Sink>> = vec![Arc::new(Prometheus)];
+
+    if let Some(url) = opts.value_of("influx_url") {
+        let token = opts.value_of("influx_token").map(str::to_string);
+        if let Some(token) = &token {
+            logging::redact(token)?;
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()?;
+        sinks.push(Arc::new(Influx {
+            client,
+            url: url.to_string(),
+            token,
+        }));
+    }
+
+    if let Some(address) = opts.value_of("statsd_address") {
+        sinks.push(Arc::new(Statsd {
+            address: address.to_string(),
+            dogstatsd: opts.value_of("statsd_flavor") == Some("dogstatsd"),
+        }));
+    }
+
+    Ok(sinks)
+}
+
+// Replaces the billing gauges served on /metrics
+#[derive(Debug)]
+pub struct Prometheus;
+
+impl Sink for Prometheus {
+    fn name(&self) -> &'static str {
+        "prometheus"
+    }
+
+    fn emit<'a>(&'a self, samples: &'a [Sample]) -> BoxFuture<'a, BoxResult<()>> {
+        metrics::set_billing(samples);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn awaited(&self) -> bool {
+        true
+    }
+}
+
+// Writes line protocol to the InfluxDB HTTP write API, v1 /write or v2 /api/v2/write
+#[derive(Debug)]
+pub struct Influx {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+// Escape commas, spaces and, outside of measurements, equal signs
+fn influx_escape(value: &str, equals: bool) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ',' || c == ' ' || (equals && c == '=') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl Influx {
+    fn lines(samples: &[Sample]) -> String {
+        let timestamp = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let mut lines = String::new();
+
+        // Line protocol has no NaN or infinity
+        for sample in samples.iter().filter(|sample| sample.value.is_finite()) {
+            lines.push_str(&influx_escape(sample.name, false));
+            // Empty tag values are rejected, a missing tag means the same
+            for (key, value) in sample.labels.iter().filter(|(_, value)| !value.is_empty()) {
+                lines.push(',');
+                lines.push_str(&influx_escape(key, true));
+                lines.push('=');
+                lines.push_str(&influx_escape(value, true));
+            }
+            lines.push_str(&format!(" value={} {timestamp}\n", sample.value));
+        }
+        lines
+    }
+}
+
+impl Sink for Influx {
+    fn name(&self) -> &'static str {
+        "influx"
+    }
+
+    fn emit<'a>(&'a self, samples: &'a [Sample]) -> BoxFuture<'a, BoxResult<()>> {
+        Box::pin(async move {
+            let request = self
+                .client
+                .post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Self::lines(samples));
+            let request = match &self.token {
+                Some(token) => {
+                    request.header(reqwest::header::AUTHORIZATION, format!("Token {token}"))
+                }
+                None => request,
+            };
+            request.send().await?.error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+// Sends gauges over UDP, with labels as DogStatsD tags or appended to the name for plain StatsD
+#[derive(Debug)]
+pub struct Statsd {
+    address: String,
+    dogstatsd: bool,
+}
+
+// Replace the characters StatsD uses as separators
+fn statsd_escape(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ':' | '|' | '@' | ',' | '#' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+impl Statsd {
+    fn line(&self, sample: &Sample) -> String {
+        let labels = sample.labels.iter().filter(|(_, value)| !value.is_empty());
+        let mut name = sample.name.to_string();
+        let mut tags = String::new();
+
+        match self.dogstatsd {
+            true => {
+                let labels: Vec<String> = labels
+                    .map(|(key, value)| format!("{key}:{}", statsd_escape(value)))
+                    .collect();
+                if !labels.is_empty() {
+                    tags = format!("|#{}", labels.join(","));
+                }
+            }
+            false => {
+                for (key, value) in labels {
+                    let value = statsd_escape(value).replace(['.', ' '], "_");
+                    name.push_str(&format!(".{key}.{value}"));
+                }
+            }
+        }
+
+        // A signed gauge value is read as a change, so negative values are set from zero
+        match sample.value < 0.0 {
+            true => format!("{name}:0|g{tags}\n{name}:{}|g{tags}", sample.value),
+            false => format!("{name}:{}|g{tags}", sample.value),
+        }
+    }
+}
+
+impl Sink for Statsd {
+    fn name(&self) -> &'static str {
+        "statsd"
+    }
+
+    fn emit<'a>(&'a self, samples: &'a [Sample]) -> BoxFuture<'a, BoxResult<()>> {
+        Box::pin(async move {
+            // Resolved on every refresh, so a moved aggregator is picked up
+            let target = tokio::net::lookup_host(&self.address)
+                .await?
+                .next()
+                .ok_or("statsd address did not resolve")?;
+            let local: SocketAddr = match target {
+                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            let socket = UdpSocket::bind(local).await?;
+            socket.connect(target).await?;
+
+            // Pack as many lines into each datagram as fit
+            let mut datagram = String::new();
+            for sample in samples.iter().filter(|sample| sample.value.is_finite()) {
+                let line = self.line(sample);
+                if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM {
+                    socket.send(datagram.as_bytes()).await?;
+                    datagram.clear();
+                }
+                if !datagram.is_empty() {
+                    datagram.push('\n');
+                }
+                datagram.push_str(&line);
+            }
+            if !datagram.is_empty() {
+                socket.send(datagram.as_bytes()).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(value: f64) -> Sample {
+        Sample {
+            name: "atlas_billing_item_cents_total",
+            value,
+            labels: vec![
+                ("cluster_name", "prod, eu".to_string()),
+                ("group_name", String::new()),
+                ("sku", "a=b:c|d".to_string()),
+            ],
+        }
+    }
+
+    #[test]
+    fn escapes_influx_line_protocol() {
+        assert_eq!(influx_escape("a b,c=d", true), r"a\ b\,c\=d");
+        assert_eq!(influx_escape("a b,c=d", false), r"a\ b\,c=d");
+    }
+
+    #[test]
+    fn writes_influx_lines_without_empty_tags() {
+        let lines = Influx::lines(&[sample(12.5), sample(f64::NAN)]);
+        let lines: Vec<&str> = lines.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let (line, timestamp) = lines[0].rsplit_once(' ').unwrap();
+        assert_eq!(
+            line,
+            r"atlas_billing_item_cents_total,cluster_name=prod\,\ eu,sku=a\=b:c|d value=12.5"
+        );
+        assert!(timestamp.parse::<i64>().is_ok());
+    }
+
+    #[test]
+    fn writes_dogstatsd_tags() {
+        let statsd = Statsd {
+            address: String::new(),
+            dogstatsd: true,
+        };
+        assert_eq!(
+            statsd.line(&sample(3.0)),
+            "atlas_billing_item_cents_total:3|g|#cluster_name:prod_ eu,sku:a=b_c_d"
+        );
+    }
+
+    #[test]
+    fn appends_labels_to_plain_statsd_names() {
+        let statsd = Statsd {
+            address: String::new(),
+            dogstatsd: false,
+        };
+        assert_eq!(
+            statsd.line(&sample(3.0)),
+            "atlas_billing_item_cents_total.cluster_name.prod__eu.sku.a=b_c_d:3|g"
+        );
+    }
+
+    #[test]
+    fn sets_negative_statsd_gauges_from_zero() {
+        let statsd = Statsd {
+            address: String::new(),
+            dogstatsd: false,
+        };
+        let sample = Sample {
+            name: "credit",
+            value: -2.5,
+            labels: Vec::new(),
+        };
+        assert_eq!(statsd.line(&sample), "credit:0|g\ncredit:-2.5|g");
+    }
+}
@@ -1,65 +1,51 @@
 use crate::https::HttpsClient;
 use chrono::Datelike;
-use chrono::Utc;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
 use clap::ArgMatches;
-use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, AUTHORIZATION, RETRY_AFTER};
 use hyper::{Body, Request, Response};
 use std::error::Error;
 //use serde_json::{Value};
 use digest_auth::AuthContext;
 //use url::Url;
-use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::field::Empty;
+use tracing::Span;
 
 use crate::create_https_client;
-use crate::error::Error as RestError;
+use crate::error::{AtlasError, Error as RestError, ErrorBody};
+use crate::invoice::{Aggregate, Compressed, Data, Filter, InvoiceSelector, RawData, Snapshot};
+use crate::logging;
+use crate::metrics::increment_counter;
+use crate::remote_write::RemoteWrite;
+use crate::shutdown::Shutdown;
+use crate::sink::{self, Sink};
+use crate::store::{History, Store};
 
 type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
 
 static URL: &str = "https://cloud.mongodb.com/api/atlas/v1.0";
+const INVOICES_PER_PAGE: u32 = 500;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-pub struct Data {
-    amount_billed_cents: u64,
-    amount_paid_cents: u64,
-    created: String,
-    credits_cents: u64,
-    end_date: String,
-    id: String,
-    line_items: Vec<LineItem>,
+// Parse an Atlas response body in its own span, large invoices take a while
+#[tracing::instrument(name = "parse_json", skip_all, fields(bytes = bytes.len()))]
+fn parse_json<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, RestError> {
+    Ok(serde_json::from_slice(bytes)?)
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-pub struct LineItem {
-    cluster_name: Option<String>,
-    created: String,
-    end_date: String,
-    quantity: f64,
-    group_name: Option<String>,
-    sku: String,
-    start_date: String,
-    tags: Option<Option<HashMap<String, Vec<String>>>>,
-    total_price_cents: u64,
-    unit: String,
-    unit_price_dollars: f64,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-pub struct Compressed {
-    cluster_name: Option<String>,
-    quantity: f64,
-    group_name: Option<String>,
-    sku: String,
-    total_price_cents: u64,
-    unit: String,
-    unit_price_dollars: f64,
-    tags: Option<Option<HashMap<String, Vec<String>>>>,
-    end_date: String,
-    start_date: String,
+// Outcome of the most recent billing fetches, used for readiness
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Refresh {
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_error: Option<Box<ErrorBody>>,
 }
 
 #[derive(Clone, Debug)]
@@ -68,6 +54,26 @@ pub struct State {
     pub public_key: String,
     pub private_key: String,
     pub org: String,
+    pub store: Option<Store>,
+    pub refresh: Arc<RwLock<Refresh>>,
+    pub staleness: Duration,
+    // Held for reading by every billing refresh, so shutdown can wait for them
+    pub in_flight: Arc<tokio::sync::RwLock<()>>,
+    // Most recently fetched invoice, persisted on shutdown when a database is set
+    pub last_invoice: Arc<RwLock<Option<Data>>>,
+    // Aggregates of the most recent invoice, read by outputs that push on their own schedule
+    pub last_snapshot: Arc<RwLock<Option<Snapshot>>>,
+    // Upper bound for a single request to Atlas
+    pub request_timeout: std::time::Duration,
+    // Time between background refreshes. When set, scrapes serve the last refresh instead of
+    // calling Atlas themselves.
+    pub refresh_interval: Option<std::time::Duration>,
+    // Receiver every background refresh is pushed to, when set
+    pub remote_write: Option<RemoteWrite>,
+    // Outputs every refresh writes its samples to, Prometheus first
+    pub sinks: Vec<Arc<dyn Sink>>,
+    // Sink writes still running in the background, waited for before exiting
+    pub sink_writes: Arc<Mutex<Vec<JoinHandle<()>>>>,
 }
 
 impl State {
@@ -78,17 +84,17 @@ impl State {
             .unwrap()
             .parse()
             .unwrap_or_else(|_| {
-                eprintln!("Supplied timeout not in range, defaulting to 60");
+                tracing::warn!("timeout not in range, defaulting to 60");
                 60
             });
 
         let client = create_https_client(timeout)?;
-        let public_key = opts
+        let public_key: String = opts
             .value_of("public_key")
             .unwrap()
             .parse()
             .expect("Could not parse public_key");
-        let private_key = opts
+        let private_key: String = opts
             .value_of("private_key")
             .unwrap()
             .parse()
@@ -99,68 +105,262 @@ impl State {
             .parse()
             .expect("Could not get org id");
 
+        // Keep the keys out of the logs, whatever ends up printing them
+        logging::redact(&public_key)?;
+        logging::redact(&private_key)?;
+
+        let store = match opts.value_of("database") {
+            Some(path) => Some(Store::open(path)?),
+            None => None,
+        };
+
+        // Set how often billing data is refreshed without waiting for a scrape
+        let refresh_interval = match opts.value_of("refresh_interval") {
+            Some(interval) => match interval.parse::<u64>() {
+                Ok(secs) if secs > 0 => Some(std::time::Duration::from_secs(secs)),
+                _ => {
+                    tracing::warn!("refresh interval not in range, defaulting to 300");
+                    Some(std::time::Duration::from_secs(300))
+                }
+            },
+            None => None,
+        };
+
+        let remote_write = RemoteWrite::from_opts(&opts, timeout)?;
+        let sinks = sink::from_opts(&opts, timeout)?;
+
+        // Set how old billing data may get before the exporter is not ready
+        let staleness: i64 = opts
+            .value_of("staleness")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|_| {
+                tracing::warn!("staleness not in range, defaulting to 3600");
+                3600
+            });
+
         Ok(State {
             client,
             public_key,
             private_key,
             org,
+            store,
+            refresh: Arc::new(RwLock::new(Refresh::default())),
+            staleness: Duration::seconds(staleness),
+            in_flight: Arc::new(tokio::sync::RwLock::new(())),
+            last_invoice: Arc::new(RwLock::new(None)),
+            last_snapshot: Arc::new(RwLock::new(None)),
+            request_timeout: std::time::Duration::from_secs(timeout),
+            refresh_interval,
+            remote_write,
+            sinks,
+            sink_writes: Arc::new(Mutex::new(Vec::new())),
         })
     }
 
     pub async fn get_pending(&self) -> Result<Data, RestError> {
         let path = format!("orgs/{}/invoices/pending", self.org);
         let body = self.get(&path).await?;
-        let bytes = hyper::body::to_bytes(body.into_body()).await?;
-        let value: Data = serde_json::from_slice(&bytes)?;
-        Ok(value)
+        let bytes = self.read_body(body).await?;
+        let value: RawData = parse_json(&bytes)?;
+        value.try_into()
     }
 
     pub async fn get_last_invoice_id(&self) -> Result<String, RestError> {
         let path = format!("orgs/{}/invoices?itemsPerPage=2", self.org);
         let body = self.get(&path).await?;
-        let bytes = hyper::body::to_bytes(body.into_body()).await?;
-        let value: Value = serde_json::from_slice(&bytes)?;
+        let bytes = self.read_body(body).await?;
+        let value: Value = parse_json(&bytes)?;
 
         // Extract results array from json
-        let results = &value["results"].as_array().ok_or(RestError::NotFound)?;
+        let results = &value["results"]
+            .as_array()
+            .ok_or(RestError::MissingField("results"))?;
 
-        // Extract the id field from the last item in results array
-        let id = &results
+        // Extract the id field from the last item in results array, no results means no invoice yet
+        let id = results
             .last()
-            .ok_or(RestError::NotFound)?
+            .ok_or(RestError::NotFound(AtlasError::default()))?
             .get("id")
-            .ok_or(RestError::NotFound)?;
+            .and_then(|id| id.as_str())
+            .ok_or(RestError::MissingField("id"))?;
 
-        Ok(id.as_str().expect("Cannot unwrap id as string!").to_owned())
+        Ok(id.to_owned())
     }
 
     pub async fn get_last_invoice(&self) -> Result<Data, RestError> {
         let id = self.get_last_invoice_id().await?;
+        self.get_invoice_by_id(&id).await
+    }
 
+    pub async fn get_invoice_by_id(&self, id: &str) -> Result<Data, RestError> {
         let path = format!("orgs/{}/invoices/{}", self.org, id);
         let body = self.get(&path).await?;
-        let bytes = hyper::body::to_bytes(body.into_body()).await?;
-        let value: Data = serde_json::from_slice(&bytes)?;
-        Ok(value)
+        let bytes = self.read_body(body).await?;
+        let value: RawData = parse_json(&bytes)?;
+        value.try_into()
+    }
+
+    // Walk through every page of the invoices list and collect the ids
+    pub async fn get_invoice_ids(&self) -> Result<Vec<String>, RestError> {
+        let mut ids = Vec::new();
+        let mut page = 1;
+
+        loop {
+            let path = format!(
+                "orgs/{}/invoices?itemsPerPage={}&pageNum={}",
+                self.org, INVOICES_PER_PAGE, page
+            );
+            let body = self.get(&path).await?;
+            let bytes = self.read_body(body).await?;
+            let value: Value = parse_json(&bytes)?;
+
+            let results = value["results"]
+                .as_array()
+                .ok_or(RestError::MissingField("results"))?;
+            ids.extend(
+                results
+                    .iter()
+                    .filter_map(|r| r.get("id").and_then(|id| id.as_str()))
+                    .map(|id| id.to_owned()),
+            );
+
+            let total = value["totalCount"].as_u64().unwrap_or_default();
+            if results.is_empty() || ids.len() as u64 >= total {
+                break;
+            }
+            page += 1;
+        }
+
+        Ok(ids)
+    }
+
+    // Store every past invoice of the org in the local database
+    pub async fn backfill(&self) -> Result<usize, RestError> {
+        let store = self.store.as_ref().ok_or(RestError::StoreDisabled)?;
+        let ids = self.get_invoice_ids().await?;
+
+        tracing::info!(invoices = ids.len(), "found invoices to backfill");
+
+        for id in &ids {
+            let data = self.get_invoice_by_id(id).await?;
+            tracing::info!(
+                invoice = %id,
+                line_items = data.line_items.len(),
+                "backfilled invoice"
+            );
+            store.save_invoice(data).await?;
+        }
+
+        Ok(ids.len())
+    }
+
+    pub async fn get_history(
+        &self,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> Result<Vec<History>, RestError> {
+        let store = self.store.as_ref().ok_or(RestError::StoreDisabled)?;
+        store.history(from, to).await
     }
 
     pub async fn get(&self, path: &str) -> Result<Response<Body>, RestError> {
+        let response = self.get_raw(path).await?;
+
+        let status = response.status().as_u16();
+        if status == 200 {
+            return Ok(response);
+        }
+
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        let atlas = AtlasError::read(response, self.request_timeout).await;
+        let error_code = atlas
+            .error_code
+            .clone()
+            .unwrap_or_else(|| status.to_string());
+        increment_counter(
+            "atlas_billing_upstream_errors_total",
+            &[("error_code", error_code)],
+        );
+
+        match status {
+            404 => Err(RestError::NotFound(atlas)),
+            403 => Err(RestError::Forbidden(atlas)),
+            401 => Err(RestError::Unauthorized(atlas)),
+            429 => Err(RestError::RateLimited(retry_after, atlas)),
+            500..=599 => Err(RestError::UpstreamUnavailable(status, atlas)),
+            _ => Err(RestError::UnknownCode(status, atlas)),
+        }
+    }
+
+    // Send an unauthenticated request, used to check if Atlas can be reached
+    pub async fn get_unauthenticated(&self, path: &str) -> Result<Response<Body>, RestError> {
+        let uri = format!("{URL}/{path}");
+        let req = Request::builder()
+            .method("GET")
+            .uri(&uri)
+            .body(Body::empty())?;
+
+        self.send(req, path, 1).await
+    }
+
+    // Send a request to Atlas, giving up once the timeout passes. For authenticated requests
+    // attempt 1 fetches the digest challenge and attempt 2 carries the answer.
+    #[tracing::instrument(
+        name = "atlas_request",
+        skip_all,
+        fields(path = %path, attempt = attempt, status = Empty, latency_ms = Empty)
+    )]
+    async fn send(
+        &self,
+        req: Request<Body>,
+        path: &str,
+        attempt: u32,
+    ) -> Result<Response<Body>, RestError> {
+        let start = Instant::now();
+        let result =
+            match tokio::time::timeout(self.request_timeout, self.client.request(req)).await {
+                Ok(Ok(response)) => Ok(response),
+                Ok(Err(e)) => Err(RestError::Hyper(e)),
+                Err(_) => Err(RestError::Timeout),
+            };
+
+        let span = Span::current();
+        span.record("latency_ms", start.elapsed().as_millis() as u64);
+        if let Ok(response) = &result {
+            span.record("status", response.status().as_u16());
+        }
+        result
+    }
+
+    // Read a whole response body, which gets the same timeout as the response headers
+    async fn read_body(&self, response: Response<Body>) -> Result<Bytes, RestError> {
+        match tokio::time::timeout(
+            self.request_timeout,
+            hyper::body::to_bytes(response.into_body()),
+        )
+        .await
+        {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(RestError::Timeout),
+        }
+    }
+
+    // Run the digest handshake and return the authenticated response, whatever its status
+    #[tracing::instrument(name = "digest_handshake", skip_all, fields(path = %path))]
+    pub async fn get_raw(&self, path: &str) -> Result<Response<Body>, RestError> {
         let uri = format!("{URL}/{path}");
-        log::debug!("getting initial response {}", &uri);
         let req = Request::builder()
             .method("GET")
             .uri(&uri)
-            .body(Body::empty())
-            .expect("request builder");
+            .body(Body::empty())?;
 
         // Send initial request
-        let response = match self.client.request(req).await {
-            Ok(s) => s,
-            Err(e) => {
-                log::error!("{{\"error\":\"{}\"", e);
-                return Err(RestError::Hyper(e));
-            }
-        };
+        let response = self.send(req, path, 1).await?;
 
         // Get digest headers, we are expecting a 401 status code
         let mut www_auth_header = match response.status().as_u16() {
@@ -169,11 +369,11 @@ impl State {
                     digest_auth::parse(www_authenticate.to_str().unwrap_or("error"))?
                 }
                 None => {
-                    log::error!("Inital request did not yield www-authenticate header");
+                    tracing::error!("initial request did not yield www-authenticate header");
                     return Err(RestError::MissingHeader);
                 }
             },
-            _ => return Err(RestError::UnexpectedCode),
+            status => return Err(RestError::UnexpectedCode(status)),
         };
 
         // Generate Digest Header Context
@@ -183,182 +383,193 @@ impl State {
         let answer = www_auth_header.respond(&context)?;
         let header_digest_auth = HeaderValue::from_str(&answer.to_string())?;
 
-        log::debug!("Using digest header for authenticated request{}", &uri);
         let mut req2 = Request::builder()
             .method("GET")
             .uri(&uri)
-            .body(Body::empty())
-            .expect("request builder");
+            .body(Body::empty())?;
 
         // Add auth header to second request
         req2.headers_mut().insert(AUTHORIZATION, header_digest_auth);
 
-        // Send initial request
-        let response2 = match self.client.request(req2).await {
-            Ok(s) => s,
-            Err(e) => {
-                log::error!("{{\"error\":\"{}\"", e);
-                return Err(RestError::Hyper(e));
-            }
-        };
-
-        match response2.status().as_u16() {
-            404 => Err(RestError::NotFound),
-            403 => Err(RestError::Forbidden),
-            401 => Err(RestError::Unauthorized),
-            200 => Ok(response2),
-            _ => {
-                log::error!(
-                    "Got bad status code getting config: {}",
-                    response.status().as_u16()
-                );
-                Err(RestError::UnknownCode)
-            }
-        }
+        // Send authenticated request
+        self.send(req2, path, 2).await
     }
 
-    pub async fn get_metrics(&self) -> Result<(), RestError> {
+    pub async fn get_invoice(&self) -> Result<Data, RestError> {
         let day = Utc::now().date_naive().day();
 
-        log::debug!("We are on the {} day of the month", day);
+        tracing::debug!("We are on the {} day of the month", day);
 
         let data = match day {
             1 => self.get_last_invoice().await?,
             _ => self.get_pending().await?,
         };
 
-        log::debug!("data: {:?}", data);
+        tracing::debug!("data: {:?}", data);
 
-        let mut map_total: HashMap<String, Compressed> = HashMap::new();
-        let mut map_rate: HashMap<String, Compressed> = HashMap::new();
+        Ok(data)
+    }
 
-        // Get most recent metric date across all metrics
-        let current_date = match data.line_items.iter().max_by_key(|y| y.end_date.clone()) {
-            Some(i) => i.end_date.clone(),
-            None => return Ok(()),
-        };
+    pub async fn get_selected_invoice(
+        &self,
+        selector: &InvoiceSelector,
+    ) -> Result<Data, RestError> {
+        match selector {
+            InvoiceSelector::Current => self.get_invoice().await,
+            InvoiceSelector::Pending => self.get_pending().await,
+            InvoiceSelector::Previous => self.get_last_invoice().await,
+            InvoiceSelector::Id(id) => self.get_invoice_by_id(id).await,
+        }
+    }
 
-        for item in data.line_items {
-            let name = match &item.cluster_name {
-                Some(e) => format!("{}_{}", e, item.sku),
-                None => item.sku.to_string(),
-            };
+    pub async fn get_line_items(
+        &self,
+        selector: &InvoiceSelector,
+        filter: &Filter,
+        aggregate: Aggregate,
+    ) -> Result<Vec<Compressed>, RestError> {
+        let data = self.get_selected_invoice(selector).await?;
+        let items = data.filter(filter).aggregate_sorted(aggregate);
+        Ok(items)
+    }
 
-            log::debug!("Working on {} from {}", name, item.end_date);
+    pub async fn get_daily(&self) -> Result<Vec<Compressed>, RestError> {
+        let data = self.get_invoice().await?;
+        Ok(data.aggregate_sorted(Aggregate::Daily))
+    }
 
-            // Add metric to the total HashMap
-            match map_total.get_mut(&name) {
-                Some(k) => {
-                    log::debug!("Found existing {} in map_total, adding up total", &name);
+    // Current refresh status, or why the exporter is not ready
+    pub fn readiness(&self) -> Result<Refresh, Refresh> {
+        let refresh = self.refresh.read().map(|r| r.clone()).unwrap_or_default();
 
-                    // Atlas prices sku's per region, so we need to get the sum
-                    k.total_price_cents += item.total_price_cents;
-                    k.quantity += item.quantity;
-                }
-                None => {
-                    log::debug!("Did not find existing {} in map_total", &name);
-                    let value = Compressed {
-                        cluster_name: item.cluster_name.clone(),
-                        quantity: item.quantity,
-                        sku: item.sku.clone(),
-                        group_name: item.group_name.clone(),
-                        total_price_cents: item.total_price_cents,
-                        unit: item.unit.clone(),
-                        unit_price_dollars: item.unit_price_dollars,
-                        tags: item.tags.clone(),
-                        start_date: item.start_date.clone(),
-                        end_date: item.end_date.clone(),
-                    };
-                    map_total.insert(name.clone(), value);
+        match refresh.last_success {
+            Some(date) if Utc::now() - date <= self.staleness => Ok(refresh),
+            _ => Err(refresh),
+        }
+    }
+
+    fn record_refresh<T>(&self, result: &Result<T, RestError>) {
+        if let Ok(mut refresh) = self.refresh.write() {
+            match result {
+                Ok(_) => refresh.last_success = Some(Utc::now()),
+                Err(e) => {
+                    refresh.last_failure = Some(Utc::now());
+                    refresh.last_error = Some(Box::new(e.body()));
                 }
             }
+        }
+    }
+
+    #[tracing::instrument(name = "refresh", skip_all, fields(invoice = Empty, ok = Empty))]
+    pub async fn get_metrics(&self) -> Result<Snapshot, RestError> {
+        let _in_flight = self.in_flight.read().await;
+        let result = self.collect_metrics().await;
+        self.record_refresh(&result);
+
+        let span = Span::current();
+        span.record("ok", result.is_ok());
+        if let Ok(snapshot) = &result {
+            span.record("invoice", snapshot.invoice_id.as_str());
+        }
+        result
+    }
 
-            // Only include metric if the end_date is today
-            if item.end_date == current_date {
-                // Add most recent metrics to hashmap
-                match map_rate.get_mut(&name) {
-                    Some(k) => {
-                        log::debug!("Found existing {} in map_rate", &name);
-                        // This metric has the same start date, indicating a SKU present in multiple regions
-                        // Therefore, get the sum of all
-                        // Atlas prices sku's per region, so we need to get the sum
-                        k.unit_price_dollars += item.unit_price_dollars;
-                        log::debug!("{} is already set in map_rate, and has the same end_date. Adding up total price to get {}", &name, k.unit_price_dollars);
-                    }
-                    None => {
-                        log::debug!("Did not find existing {} in map_rate", &name);
-                        let value = Compressed {
-                            cluster_name: item.cluster_name.clone(),
-                            quantity: item.quantity,
-                            sku: item.sku.clone(),
-                            group_name: item.group_name.clone(),
-                            total_price_cents: item.total_price_cents,
-                            unit: item.unit.clone(),
-                            unit_price_dollars: item.unit_price_dollars,
-                            tags: item.tags.clone(),
-                            start_date: item.start_date.clone(),
-                            end_date: item.end_date.clone(),
-                        };
-                        map_rate.insert(name, value);
-                    }
+    // Refresh billing data every interval until shutdown, starting right away, and push it to
+    // the remote_write receiver when one is set
+    pub async fn refresh_every(&self, interval: std::time::Duration, shutdown: Shutdown) {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = shutdown.wait() => return,
+            }
+            let snapshot = match self.get_metrics().await {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    e.body().log("refresh failed");
+                    continue;
+                }
+            };
+            if let Some(remote_write) = &self.remote_write {
+                // Held like a refresh so shutdown waits for the push within its deadline
+                let _in_flight = self.in_flight.read().await;
+                if let Err(e) = remote_write.push(&snapshot).await {
+                    tracing::error!(error = %e, "remote write failed");
                 }
             }
         }
+    }
 
-        log::debug!("Total: {:?}", map_total);
-        log::debug!("Rates: {:?}", map_rate);
-
-        for (_key, value) in map_total {
-            let project = match value.tags {
-                Some(inner_option) => match inner_option {
-                    Some(map) => map.get("project").and_then(|v| v.get(0)).map(|val| val.clone()),
-                    None => None,
-                },
-                None => None,
-            };            
-            let labels = [
-                ("cluster_name", value.cluster_name.unwrap_or("".to_string())),
-                ("group_name", value.group_name.unwrap_or("".to_string())),
-                ("sku", value.sku.clone()),
-                ("project", project.unwrap_or("".to_string())),
-            ];
-            metrics::gauge!(
-                "atlas_billing_item_cents_total",
-                value.total_price_cents as f64,
-                &labels
-            );
-        }
+    // Wait for sink writes still running in the background, each one is bounded by its timeout
+    pub async fn flush_sinks(&self) {
+        let writes: Vec<_> = match self.sink_writes.lock() {
+            Ok(mut writes) => writes.drain(..).collect(),
+            Err(_) => return,
+        };
+        futures::future::join_all(writes).await;
+    }
 
-        for (_key, value) in map_rate {
-            let project = match value.tags {
-                Some(inner_option) => match inner_option {
-                    Some(map) => map.get("project").and_then(|v| v.get(0)).map(|val| val.clone()),
-                    None => None,
-                },
-                None => None,
-            };           
-            let labels = [
-                ("cluster_name", value.cluster_name.unwrap_or("".to_string())),
-                ("group_name", value.group_name.unwrap_or("".to_string())),
-                ("sku", value.sku.clone()),
-                ("project", project.unwrap_or("".to_string())),
-            ];
-
-            if value.unit == "GB hours" || value.unit == "server hours" {
-                // Get overall rate in cents per hour
-                metrics::gauge!(
-                    "atlas_billing_item_cents_rate",
-                    value.unit_price_dollars,
-                    &labels
-                );
-            } else {
-                // Convert cents per day to cents per hour
-                // Get overall rate in cents per hour
-                let rate = value.total_price_cents as f64 / value.quantity / 100.0 / 24.0;
-                metrics::gauge!("atlas_billing_item_cents_rate", rate, &labels);
+    // Wait for in-flight refreshes, remote writes and sink writes, then persist the last invoice
+    // if a database is set
+    pub async fn shutdown(&self, deadline: std::time::Duration) -> Result<(), RestError> {
+        let finished = async {
+            let in_flight = self.in_flight.write().await;
+            self.flush_sinks().await;
+            in_flight
+        };
+        let _in_flight = match tokio::time::timeout(deadline, finished).await {
+            Ok(guard) => guard,
+            Err(_) => {
+                tracing::warn!("billing refresh still running at deadline");
+                return Ok(());
             }
+        };
+
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+
+        let data = self.last_invoice.read().ok().and_then(|d| d.clone());
+        if let Some(data) = data {
+            tracing::info!(invoice = %data.id, "persisting invoice");
+            store.save_invoice(data).await?;
         }
 
         Ok(())
     }
+
+    async fn collect_metrics(&self) -> Result<Snapshot, RestError> {
+        let data = self.get_invoice().await?;
+        if let Ok(mut last_invoice) = self.last_invoice.write() {
+            *last_invoice = Some(data.clone());
+        }
+        let snapshot = Snapshot::from(&data);
+
+        tracing::debug!("Total: {:?}", snapshot.totals);
+        tracing::debug!("Rates: {:?}", snapshot.rates);
+        tracing::debug!("Daily: {:?}", snapshot.daily);
+
+        for (value, e) in snapshot.unknown_units() {
+            tracing::warn!(sku = %value.sku, "{}", e);
+            increment_counter(
+                "atlas_billing_unknown_units_total",
+                &[("unit", value.unit.clone())],
+            );
+        }
+
+        let writes = sink::emit_all(&self.sinks, snapshot.samples()).await;
+        if let Ok(mut pending) = self.sink_writes.lock() {
+            pending.retain(|write| !write.is_finished());
+            pending.extend(writes);
+        }
+
+        if let Ok(mut last_snapshot) = self.last_snapshot.write() {
+            *last_snapshot = Some(snapshot.clone());
+        }
+
+        Ok(snapshot)
+    }
 }
@@ -0,0 +1,167 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+use crate::error::Error as RestError;
+use crate::invoice::Data;
+
+static SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS invoices (
+        id TEXT PRIMARY KEY,
+        created TEXT NOT NULL,
+        end_date TEXT NOT NULL,
+        amount_billed_cents INTEGER NOT NULL,
+        amount_paid_cents INTEGER NOT NULL,
+        credits_cents INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS line_items (
+        invoice_id TEXT NOT NULL REFERENCES invoices(id) ON DELETE CASCADE,
+        cluster_name TEXT NOT NULL,
+        group_name TEXT NOT NULL,
+        sku TEXT NOT NULL,
+        project TEXT NOT NULL,
+        tags TEXT NOT NULL,
+        unit TEXT NOT NULL,
+        quantity REAL NOT NULL,
+        unit_price_dollars REAL NOT NULL,
+        total_price_cents INTEGER NOT NULL,
+        start_date TEXT NOT NULL,
+        end_date TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS line_items_start_date ON line_items(start_date);
+    CREATE INDEX IF NOT EXISTS line_items_invoice_id ON line_items(invoice_id);
+";
+
+// Monthly cost per cluster and sku, as stored by backfill
+#[derive(Serialize, Debug, Clone)]
+pub struct History {
+    pub month: String,
+    pub cluster_name: String,
+    pub group_name: String,
+    pub sku: String,
+    pub project: String,
+    pub quantity: f64,
+    pub total_price_cents: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Store {
+    pub fn open(path: &str) -> Result<Self, RestError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(Store {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // Run a closure against the connection on the blocking thread pool
+    async fn with_conn<F, T>(&self, f: F) -> Result<T, RestError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().map_err(|_| RestError::StorePoisoned)?;
+            f(&mut conn).map_err(RestError::from)
+        })
+        .await?
+    }
+
+    // Replace the stored copy of an invoice and its line items
+    pub async fn save_invoice(&self, data: Data) -> Result<(), RestError> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+
+            tx.execute("DELETE FROM invoices WHERE id = ?1", params![data.id])?;
+            tx.execute(
+                "INSERT INTO invoices (id, created, end_date, amount_billed_cents, amount_paid_cents, credits_cents)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    data.id,
+                    data.created,
+                    data.end_date,
+                    data.amount_billed_cents,
+                    data.amount_paid_cents,
+                    data.credits_cents
+                ],
+            )?;
+
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT INTO line_items (invoice_id, cluster_name, group_name, sku, project, tags, unit, quantity, unit_price_dollars, total_price_cents, start_date, end_date)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                )?;
+
+                for item in &data.line_items {
+                    let tags = serde_json::to_string(&item.tags).unwrap_or_default();
+                    stmt.execute(params![
+                        data.id,
+                        item.cluster_name.clone().unwrap_or_default(),
+                        item.group_name.clone().unwrap_or_default(),
+                        item.sku,
+                        item.project().unwrap_or_default(),
+                        tags,
+                        item.unit,
+                        item.quantity,
+                        item.unit_price_dollars,
+                        item.total_price_cents,
+                        item.start_date,
+                        item.end_date
+                    ])?;
+                }
+            }
+
+            tx.commit()
+        })
+        .await
+    }
+
+    // Monthly totals for line items starting within the given range
+    pub async fn history(
+        &self,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> Result<Vec<History>, RestError> {
+        let from: Option<DateTime<Utc>> = from
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .map(|d| d.and_utc());
+        let to: Option<DateTime<Utc>> = to
+            .and_then(|d| d.succ_opt())
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .map(|d| d.and_utc());
+
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT substr(start_date, 1, 7) AS month, cluster_name, group_name, sku, project,
+                        SUM(quantity), SUM(total_price_cents)
+                 FROM line_items
+                 WHERE (?1 IS NULL OR start_date >= ?1) AND (?2 IS NULL OR start_date < ?2)
+                 GROUP BY month, cluster_name, group_name, sku, project
+                 ORDER BY month, cluster_name, sku",
+            )?;
+
+            let rows = stmt.query_map(params![from, to], |row| {
+                Ok(History {
+                    month: row.get(0)?,
+                    cluster_name: row.get(1)?,
+                    group_name: row.get(2)?,
+                    sku: row.get(3)?,
+                    project: row.get(4)?,
+                    quantity: row.get(5)?,
+                    total_price_cents: row.get(6)?,
+                })
+            })?;
+
+            rows.collect()
+        })
+        .await
+    }
+}
@@ -0,0 +1,217 @@
+use axum::http::Request;
+use clap::{crate_name, crate_version, ArgMatches};
+use opentelemetry::metrics::{CallbackRegistration, MeterProvider as _, ObservableGauge};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::metrics::MeterProvider;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::resource::EnvResourceDetector;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use std::error::Error;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tower_http::trace::MakeSpan;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::State;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Billing gauges pushed over OTLP, the same series /metrics serves
+const BILLING_GAUGES: [&str; 4] = [
+    "atlas_billing_item_cents_total",
+    "atlas_billing_item_cents_rate",
+    "atlas_billing_item_unit_price_cents",
+    "atlas_billing_item_cents_daily",
+];
+
+// Kept until shutdown, so the last values can be flushed
+struct MetricsPush {
+    provider: MeterProvider,
+    _callback: Box<dyn CallbackRegistration>,
+}
+
+static METRICS_PUSH: OnceLock<Mutex<Option<MetricsPush>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Grpc,
+    Http,
+}
+
+// Where to send OpenTelemetry data, set with --otlp_endpoint
+#[derive(Debug, Clone)]
+pub struct Otlp {
+    pub endpoint: String,
+    pub protocol: Protocol,
+}
+
+impl Otlp {
+    pub fn from_opts(opts: &ArgMatches) -> Option<Self> {
+        let endpoint = opts.value_of("otlp_endpoint")?.to_string();
+        let protocol = match opts.value_of("otlp_protocol") {
+            Some("http") => Protocol::Http,
+            _ => Protocol::Grpc,
+        };
+        Some(Otlp { endpoint, protocol })
+    }
+
+    // Attributes from OTEL_RESOURCE_ATTRIBUTES win over ours
+    pub fn resource(&self) -> Resource {
+        let env = Resource::from_detectors(
+            Duration::from_secs(0),
+            vec![Box::new(EnvResourceDetector::new())],
+        );
+        Resource::new(vec![
+            KeyValue::new("service.name", crate_name!()),
+            KeyValue::new("service.version", crate_version!()),
+        ])
+        .merge(&env)
+    }
+}
+
+// Start the batch span exporter and accept W3C trace context from callers
+pub fn tracer(otlp: &Otlp) -> BoxResult<trace::Tracer> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let pipeline = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_trace_config(trace::config().with_resource(otlp.resource()));
+
+    let tracer = match otlp.protocol {
+        Protocol::Grpc => pipeline
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(&otlp.endpoint),
+            )
+            .install_batch(runtime::Tokio)?,
+        Protocol::Http => pipeline
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .http()
+                    .with_endpoint(&otlp.endpoint),
+            )
+            .install_batch(runtime::Tokio)?,
+    };
+
+    Ok(tracer)
+}
+
+// Name of this exporter instance, the pod name on Kubernetes
+fn instance() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Push the billing gauges of the latest snapshot to the collector every interval
+pub fn push_metrics(otlp: &Otlp, interval: Duration, state: &State) -> BoxResult<()> {
+    let resource = Resource::new(vec![
+        KeyValue::new("atlas.org_id", state.org.clone()),
+        KeyValue::new("service.instance.id", instance()),
+    ])
+    .merge(&otlp.resource());
+
+    let pipeline = opentelemetry_otlp::new_pipeline()
+        .metrics(runtime::Tokio)
+        .with_resource(resource)
+        .with_period(interval);
+
+    let provider = match otlp.protocol {
+        Protocol::Grpc => pipeline
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(&otlp.endpoint),
+            )
+            .build()?,
+        Protocol::Http => pipeline
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .http()
+                    .with_endpoint(&otlp.endpoint),
+            )
+            .build()?,
+    };
+
+    let meter = provider.meter(crate_name!());
+    let gauges = BILLING_GAUGES
+        .iter()
+        .map(|name| meter.f64_observable_gauge(*name).try_init())
+        .collect::<Result<Vec<ObservableGauge<f64>>, _>>()?;
+    let instruments: Vec<_> = gauges.iter().map(|gauge| gauge.as_any()).collect();
+
+    let last_snapshot = state.last_snapshot.clone();
+    let callback = meter.register_callback(&instruments, move |observer| {
+        let snapshot = match last_snapshot.read() {
+            Ok(snapshot) => snapshot.clone(),
+            Err(_) => return,
+        };
+
+        for sample in snapshot.iter().flat_map(|snapshot| snapshot.samples()) {
+            let gauge = match BILLING_GAUGES.iter().position(|name| *name == sample.name) {
+                Some(index) => &gauges[index],
+                None => continue,
+            };
+            let attributes: Vec<KeyValue> = sample
+                .labels
+                .into_iter()
+                .map(|(key, value)| KeyValue::new(key, value))
+                .collect();
+            observer.observe_f64(gauge, sample.value, &attributes);
+        }
+    })?;
+
+    let push = MetricsPush {
+        provider,
+        _callback: callback,
+    };
+    *METRICS_PUSH
+        .get_or_init(|| Mutex::new(None))
+        .lock()
+        .map_err(|_| "metrics push lock poisoned")? = Some(push);
+    Ok(())
+}
+
+// Flush spans and metrics that are still queued, a no-op when no exporter was started
+pub fn shutdown() {
+    if let Some(Ok(mut push)) = METRICS_PUSH.get().map(|push| push.lock()) {
+        if let Some(push) = push.take() {
+            if let Err(e) = push.provider.force_flush() {
+                tracing::warn!(error = %e, "could not flush metrics");
+            }
+            // The reader marks itself shut down before its last collect, so that one always
+            // fails. Everything was flushed above.
+            let _ = push.provider.shutdown();
+        }
+    }
+    global::shutdown_tracer_provider();
+}
+
+// Span per incoming request, continuing the caller's trace when it sent a traceparent header.
+// Headers are not recorded, they carry credentials.
+#[derive(Debug, Clone, Copy)]
+pub struct PropagatingMakeSpan;
+
+impl<B> MakeSpan<B> for PropagatingMakeSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        });
+
+        let span = tracing::debug_span!(
+            "request",
+            method = %request.method(),
+            uri = %request.uri(),
+            version = ?request.version(),
+        );
+        span.set_parent(parent);
+        span
+    }
+}
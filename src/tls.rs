@@ -0,0 +1,136 @@
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::web_config::{ClientAuthType, TlsServerConfig, TlsVersion};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// How often certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+fn load_certs(path: &str) -> BoxResult<Vec<Certificate>> {
+    let file = File::open(path).map_err(|e| format!("Could not open {path}: {e}"))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))?;
+
+    match certs.is_empty() {
+        true => Err(format!("No certificates found in {path}").into()),
+        false => Ok(certs.into_iter().map(Certificate).collect()),
+    }
+}
+
+fn load_key(path: &str) -> BoxResult<PrivateKey> {
+    let file = File::open(path).map_err(|e| format!("Could not open {path}: {e}"))?;
+
+    for item in rustls_pemfile::read_all(&mut BufReader::new(file))? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+
+    Err(format!("No private key found in {path}").into())
+}
+
+fn load_roots(path: &str) -> BoxResult<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert)?;
+    }
+    Ok(roots)
+}
+
+// Build a rustls config from the tls_server_config section of the web config
+pub fn server_config(config: &TlsServerConfig) -> BoxResult<ServerConfig> {
+    let versions: &[&rustls::SupportedProtocolVersion] = match config.min_version {
+        None | Some(TlsVersion::TLS12) => rustls::ALL_VERSIONS,
+        Some(TlsVersion::TLS13) => &[&rustls::version::TLS13],
+    };
+
+    let builder = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)?;
+
+    let client_ca = || -> BoxResult<RootCertStore> {
+        let path = config
+            .client_ca_file
+            .as_deref()
+            .ok_or("client_ca_file is required to verify client certificates")?;
+        load_roots(path)
+    };
+
+    let builder = match config.client_auth_type {
+        ClientAuthType::NoClientCert => builder.with_no_client_auth(),
+        ClientAuthType::VerifyClientCertIfGiven => builder.with_client_cert_verifier(
+            AllowAnyAnonymousOrAuthenticatedClient::new(client_ca()?).boxed(),
+        ),
+        ClientAuthType::RequireAndVerifyClientCert => builder
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(client_ca()?).boxed()),
+        ClientAuthType::RequestClientCert | ClientAuthType::RequireAnyClientCert => {
+            return Err(format!(
+                "client_auth_type {:?} is not supported, client certificates are always verified",
+                config.client_auth_type
+            )
+            .into())
+        }
+    };
+
+    let mut server_config =
+        builder.with_single_cert(load_certs(&config.cert_file)?, load_key(&config.key_file)?)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
+
+// Latest modification time across the certificate, key and client ca files
+fn modified(config: &TlsServerConfig) -> Option<SystemTime> {
+    [
+        Some(&config.cert_file),
+        Some(&config.key_file),
+        config.client_ca_file.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+    .max()
+}
+
+// Load the config and reload it in the background whenever the files change on disk
+pub fn rustls_config(config: TlsServerConfig) -> BoxResult<RustlsConfig> {
+    let rustls_config = RustlsConfig::from_config(Arc::new(server_config(&config)?));
+
+    let reloaded = rustls_config.clone();
+    tokio::spawn(async move {
+        let mut last_modified = modified(&config);
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let current = modified(&config);
+            if current == last_modified {
+                continue;
+            }
+
+            match server_config(&config) {
+                Ok(server_config) => {
+                    reloaded.reload_from_config(Arc::new(server_config));
+                    last_modified = current;
+                    tracing::info!("reloaded certificates");
+                }
+                // Keep serving the old certificates, files may be halfway through being replaced
+                Err(e) => tracing::error!(error = %e, "could not reload certificates"),
+            }
+        }
+    });
+
+    Ok(rustls_config)
+}
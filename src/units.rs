@@ -0,0 +1,144 @@
+use std::fmt;
+use std::str::FromStr;
+
+// Units reported by Atlas in the unit field of invoice line items
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Hours,
+    ServerHours,
+    GbHours,
+    GbDays,
+    GbMonths,
+    Gb,
+    Tb,
+    Requests,
+    MillionRequests,
+    MillionReads,
+    MillionWrites,
+    MillionRpus,
+    MillionWpus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownUnit(pub String);
+
+impl fmt::Display for UnknownUnit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown unit: {}", self.0)
+    }
+}
+
+impl FromStr for Unit {
+    type Err = UnknownUnit;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Atlas is not consistent with casing, dashes and plurals
+        let normalized = s.to_lowercase().replace(['-', '_'], " ");
+        let normalized = normalized.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        match normalized.as_str() {
+            "hour" | "hours" => Ok(Unit::Hours),
+            "server hour" | "server hours" => Ok(Unit::ServerHours),
+            "gb hour" | "gb hours" => Ok(Unit::GbHours),
+            "gb day" | "gb days" => Ok(Unit::GbDays),
+            "gb month" | "gb months" => Ok(Unit::GbMonths),
+            "gb" | "gbs" => Ok(Unit::Gb),
+            "tb" | "tbs" => Ok(Unit::Tb),
+            "request" | "requests" => Ok(Unit::Requests),
+            "million requests" => Ok(Unit::MillionRequests),
+            "million reads" => Ok(Unit::MillionReads),
+            "million writes" => Ok(Unit::MillionWrites),
+            "million rpus" => Ok(Unit::MillionRpus),
+            "million wpus" => Ok(Unit::MillionWpus),
+            _ => Err(UnknownUnit(s.to_string())),
+        }
+    }
+}
+
+impl Unit {
+    // Unit that unit prices are normalized to, used as the unit label
+    pub fn normalized(&self) -> Unit {
+        match self {
+            Unit::GbDays | Unit::GbMonths => Unit::GbHours,
+            Unit::Tb => Unit::Gb,
+            _ => *self,
+        }
+    }
+
+    // Multiplier to convert a price per this unit into a price per normalized unit
+    pub fn price_factor(&self) -> f64 {
+        match self {
+            Unit::GbDays => 1.0 / 24.0,
+            // Atlas bills monthly storage over 30 days
+            Unit::GbMonths => 1.0 / (30.0 * 24.0),
+            Unit::Tb => 1.0 / 1024.0,
+            _ => 1.0,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Unit::Hours => "hours",
+            Unit::ServerHours => "server_hours",
+            Unit::GbHours => "gigabyte_hours",
+            Unit::GbDays => "gigabyte_days",
+            Unit::GbMonths => "gigabyte_months",
+            Unit::Gb => "gigabytes",
+            Unit::Tb => "terabytes",
+            Unit::Requests => "requests",
+            Unit::MillionRequests => "million_requests",
+            Unit::MillionReads => "million_reads",
+            Unit::MillionWrites => "million_writes",
+            Unit::MillionRpus => "million_rpus",
+            Unit::MillionWpus => "million_wpus",
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_casing_dashes_and_plurals() {
+        assert_eq!("Hours".parse(), Ok(Unit::Hours));
+        assert_eq!("hour".parse(), Ok(Unit::Hours));
+        assert_eq!("SERVER_HOURS".parse(), Ok(Unit::ServerHours));
+        assert_eq!("GB-Hour".parse(), Ok(Unit::GbHours));
+        assert_eq!("gb  days".parse(), Ok(Unit::GbDays));
+        assert_eq!("GB-Months".parse(), Ok(Unit::GbMonths));
+        assert_eq!("GBs".parse(), Ok(Unit::Gb));
+        assert_eq!("TB".parse(), Ok(Unit::Tb));
+        assert_eq!("Million-Requests".parse(), Ok(Unit::MillionRequests));
+        assert_eq!("million rpus".parse(), Ok(Unit::MillionRpus));
+    }
+
+    #[test]
+    fn keeps_the_reported_unit_when_unknown() {
+        assert_eq!(
+            "Cluster-Weeks".parse::<Unit>(),
+            Err(UnknownUnit("Cluster-Weeks".to_string()))
+        );
+        assert_eq!("".parse::<Unit>(), Err(UnknownUnit(String::new())));
+    }
+
+    #[test]
+    fn normalizes_prices_to_hours_and_gigabytes() {
+        assert_eq!(Unit::GbDays.normalized(), Unit::GbHours);
+        assert_eq!(Unit::GbMonths.normalized(), Unit::GbHours);
+        assert_eq!(Unit::Tb.normalized(), Unit::Gb);
+        assert_eq!(Unit::Hours.normalized(), Unit::Hours);
+
+        assert_eq!(Unit::GbDays.price_factor() * 24.0, 1.0);
+        assert_eq!(Unit::GbMonths.price_factor() * 720.0, 1.0);
+        assert_eq!(Unit::Tb.price_factor() * 1024.0, 1.0);
+        assert_eq!(Unit::GbHours.price_factor(), 1.0);
+        assert_eq!(Unit::MillionReads.price_factor(), 1.0);
+    }
+}
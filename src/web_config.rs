@@ -0,0 +1,94 @@
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Settings for the exporter's own http server, loaded from a yaml file
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct WebConfig {
+    #[serde(default)]
+    pub auth: AuthConfig,
+    pub tls_server_config: Option<TlsServerConfig>,
+}
+
+// Follows the tls_server_config section of the Prometheus exporter-toolkit web config
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsServerConfig {
+    pub cert_file: String,
+    pub key_file: String,
+    #[serde(default)]
+    pub client_auth_type: ClientAuthType,
+    pub client_ca_file: Option<String>,
+    pub min_version: Option<TlsVersion>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientAuthType {
+    #[default]
+    NoClientCert,
+    RequestClientCert,
+    RequireAnyClientCert,
+    VerifyClientCertIfGiven,
+    RequireAndVerifyClientCert,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    TLS12,
+    TLS13,
+}
+
+// Authentication per route group, groups without settings stay open
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    // /metrics
+    pub metrics: Option<GroupAuth>,
+    // /, /daily, /history, /status, /api/v1/line-items and /export/csv
+    pub api: Option<GroupAuth>,
+    // /health, /ready and /help
+    pub health: Option<GroupAuth>,
+}
+
+// Either credential is accepted when both are set
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct GroupAuth {
+    pub basic_auth: Option<BasicAuthConfig>,
+    pub bearer_token_file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuthConfig {
+    pub username: String,
+    pub password_file: String,
+}
+
+impl WebConfig {
+    pub fn load(path: Option<&str>) -> BoxResult<Self> {
+        match path {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| format!("Could not read web config {path}: {e}"))?;
+                Ok(serde_yaml::from_str(&contents)?)
+            }
+            None => Ok(WebConfig::default()),
+        }
+    }
+}
+
+// Read a secret from a file, dropping the trailing newline editors like to add
+pub fn read_secret(path: &str) -> BoxResult<String> {
+    let secret =
+        fs::read_to_string(path).map_err(|e| format!("Could not read secret file {path}: {e}"))?;
+    let secret = secret.trim_end_matches(['\r', '\n']).to_string();
+
+    match secret.is_empty() {
+        true => Err(format!("Secret file {path} is empty").into()),
+        false => Ok(secret),
+    }
+}